@@ -1,22 +1,244 @@
 // Prevents additional console window on Windows in release, DO NOT REMOVE!!
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")]
 
-use reqly::{HttpRequest, HttpResponse, send_http_request};
+use std::sync::Arc;
+use std::time::Duration;
+
+use reqly::{HttpRequest, HttpResponse, UdpManager, WebSocketManager, send_http_request};
 use tauri::command;
 
+/// The WebSocket connections opened by the frontend.
+///
+/// Not behind a lock either: a receive waiting for the next message must not hold up the
+/// other commands.
+pub struct WebSocketState(Arc<WebSocketManager>);
+
+/// The UDP socket used by the frontend.
+///
+/// Not behind a lock: sending while a receive is pending must not wait for it to finish.
+pub struct UdpState(UdpManager);
+
 #[command]
 fn perform_http_request(request: HttpRequest) -> Result<HttpResponse, String> {
     send_http_request(request)
 }
 
+#[command]
+async fn ws_connect(url: String, state: tauri::State<'_, WebSocketState>) -> Result<usize, String> {
+    state.0.connect(&url).await.map_err(|e| e.to_string())
+}
+
+#[command]
+async fn ws_send(id: usize, message: String, state: tauri::State<'_, WebSocketState>) -> Result<(), String> {
+    state.0.send_message(id, message).await.map_err(|e| e.to_string())
+}
+
+#[command]
+async fn ws_close(id: usize, state: tauri::State<'_, WebSocketState>) -> Result<(), String> {
+    state.0.close_connection(id).await;
+    Ok(())
+}
+
+/// Forwards incoming messages of a connection to the frontend as `ws-message-{id}` events.
+///
+/// A background task waits for each message and emits it as soon as it arrives; the events
+/// are also triggered for listeners on the Rust side of the window. Delivery stops once the
+/// connection is closed.
+#[command]
+async fn ws_enable_events<R: tauri::Runtime>(
+    id: usize,
+    window: tauri::Window<R>,
+    state: tauri::State<'_, WebSocketState>,
+) -> Result<(), String> {
+    let manager = state.0.clone();
+    tauri::async_runtime::spawn(async move {
+        while let Ok(Some(message)) = manager.receive_message(id, None).await {
+            if window.emit_and_trigger(&format!("ws-message-{}", id), message).is_err() {
+                break;
+            }
+        }
+    });
+    Ok(())
+}
+
+#[command]
+async fn udp_send(message: Vec<u8>, addr: String, state: tauri::State<'_, UdpState>) -> Result<(), String> {
+    state.0.send_message(message, &addr).await.map_err(|e| e.to_string())
+}
+
+#[command]
+async fn udp_receive(timeout_ms: Option<u64>, state: tauri::State<'_, UdpState>) -> Result<(Vec<u8>, String), String> {
+    let receive = state.0.receive_message();
+    let result = match timeout_ms {
+        Some(timeout_ms) => tokio::time::timeout(Duration::from_millis(timeout_ms), receive)
+            .await
+            .map_err(|_| format!("no UDP message received within {} ms", timeout_ms))?,
+        None => receive.await,
+    };
+    result.map_err(|e| e.to_string())
+}
+
 #[tauri::command]
 fn greet(name: &str) -> String {
     format!("Hello, {}! You've been greeted from Rust!", name)
 }
 
+/// Adds the state and commands of the app to `builder`, which the tests build on a mock runtime.
+fn setup<R: tauri::Runtime>(builder: tauri::Builder<R>) -> tauri::Builder<R> {
+    // The manager spawns its background task, so it has to be created inside the runtime.
+    let websockets = tauri::async_runtime::block_on(async { WebSocketManager::new() });
+    let udp = tauri::async_runtime::block_on(UdpManager::new("0.0.0.0:0"))
+        .expect("failed to bind UDP socket");
+
+    builder
+        .manage(WebSocketState(Arc::new(websockets)))
+        .manage(UdpState(udp))
+        .invoke_handler(tauri::generate_handler![
+            greet,
+            perform_http_request,
+            ws_connect,
+            ws_send,
+            ws_close,
+            ws_enable_events,
+            udp_send,
+            udp_receive
+        ])
+}
+
 fn main() {
-    tauri::Builder::default()
-        .invoke_handler(tauri::generate_handler![greet, perform_http_request])
+    setup(tauri::Builder::default())
         .run(tauri::generate_context!())
         .expect("error while running tauri application");
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::{SinkExt, StreamExt};
+    use reqly::WsConnectionState;
+    use serde_json::{json, Value};
+    use tauri::api::ipc::CallbackFn;
+    use tauri::test::{get_ipc_response, mock_builder, mock_context, noop_assets, MockRuntime};
+    use tauri::{App, InvokePayload, Manager, Window};
+    use tokio::net::TcpListener;
+    use tokio_tungstenite::accept_async;
+
+    fn mock_app() -> App<MockRuntime> {
+        setup(mock_builder()).build(mock_context(noop_assets())).unwrap()
+    }
+
+    /// Invokes `cmd` the way the frontend does, returning its JSON result or error.
+    fn invoke(window: &Window<MockRuntime>, cmd: &str, args: Value) -> Result<Value, Value> {
+        get_ipc_response(
+            window,
+            InvokePayload {
+                cmd: cmd.into(),
+                tauri_module: None,
+                callback: CallbackFn(0),
+                error: CallbackFn(1),
+                inner: args,
+            },
+        )
+    }
+
+    /// Starts a local WebSocket server that echoes text messages.
+    fn spawn_echo_server() -> String {
+        tauri::async_runtime::block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            tauri::async_runtime::spawn(async move {
+                while let Ok((stream, _)) = listener.accept().await {
+                    tauri::async_runtime::spawn(async move {
+                        let mut ws = accept_async(stream).await.unwrap();
+                        while let Some(Ok(msg)) = ws.next().await {
+                            if msg.is_text() {
+                                let _ = ws.send(msg).await;
+                            }
+                        }
+                    });
+                }
+            });
+            format!("ws://{}", addr)
+        })
+    }
+
+    #[test]
+    fn test_ws_commands() {
+        let url = spawn_echo_server();
+        let app = mock_app();
+        let window = app.get_window("main").unwrap();
+        let state = window.state::<WebSocketState>();
+
+        let id = invoke(&window, "ws_connect", json!({ "url": url })).unwrap();
+        let id = id.as_u64().unwrap() as usize;
+        let manager = state.0.clone();
+        let connection_state = tauri::async_runtime::block_on(async move { manager.state(id).await });
+        assert_eq!(connection_state, WsConnectionState::Open);
+
+        assert_eq!(invoke(&window, "ws_send", json!({ "id": id, "message": "hello" })), Ok(Value::Null));
+        let manager = state.0.clone();
+        let echoed = tauri::async_runtime::block_on(async move {
+            manager.receive_message(id, Some(1000)).await.unwrap()
+        });
+        assert_eq!(echoed, Some(reqly::WsMessage::Text("hello".to_string())));
+
+        assert_eq!(invoke(&window, "ws_close", json!({ "id": id })), Ok(Value::Null));
+        let manager = state.0.clone();
+        let (connection_state, count) = tauri::async_runtime::block_on(async move {
+            (manager.state(id).await, manager.connection_count().await)
+        });
+        assert_eq!(connection_state, WsConnectionState::Closed);
+        assert_eq!(count, 0);
+
+        assert!(invoke(&window, "ws_connect", json!({ "url": "not a url" })).is_err());
+    }
+
+    #[test]
+    fn test_ws_events() {
+        let url = spawn_echo_server();
+        let app = mock_app();
+        let window = app.get_window("main").unwrap();
+        let id = invoke(&window, "ws_connect", json!({ "url": url })).unwrap();
+
+        let (events, received) = std::sync::mpsc::channel();
+        window.listen(format!("ws-message-{}", id), move |event| {
+            let _ = events.send(event.payload().map(str::to_string));
+        });
+        assert_eq!(invoke(&window, "ws_enable_events", json!({ "id": id })), Ok(Value::Null));
+
+        for message in ["first", "second"] {
+            assert_eq!(invoke(&window, "ws_send", json!({ "id": id, "message": message })), Ok(Value::Null));
+            let payload = received.recv_timeout(Duration::from_secs(1)).unwrap().unwrap();
+            let payload: Value = serde_json::from_str(&payload).unwrap();
+            assert_eq!(payload, json!({ "type": "text", "data": message }));
+        }
+
+        // The pending receive of the event loop does not hold up closing the connection.
+        let started = std::time::Instant::now();
+        assert_eq!(invoke(&window, "ws_close", json!({ "id": id })), Ok(Value::Null));
+        assert!(started.elapsed() < Duration::from_secs(1));
+        assert!(received.recv_timeout(Duration::from_millis(200)).is_err());
+    }
+
+    #[test]
+    fn test_udp_commands() {
+        let server = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let server_addr = server.local_addr().unwrap().to_string();
+        std::thread::spawn(move || {
+            let mut buffer = [0; 1024];
+            while let Ok((len, peer)) = server.recv_from(&mut buffer) {
+                let _ = server.send_to(&buffer[..len], peer);
+            }
+        });
+        let app = mock_app();
+        let window = app.get_window("main").unwrap();
+
+        let message = json!({ "message": [1, 2, 3], "addr": server_addr });
+        assert_eq!(invoke(&window, "udp_send", message), Ok(Value::Null));
+        let received = invoke(&window, "udp_receive", json!({ "timeoutMs": 1000 }));
+        assert_eq!(received, Ok(json!([[1, 2, 3], server_addr])));
+
+        let received = invoke(&window, "udp_receive", json!({ "timeoutMs": 50 }));
+        assert_eq!(received, Err(json!("no UDP message received within 50 ms")));
+    }
+}
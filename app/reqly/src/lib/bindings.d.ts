@@ -0,0 +1,41 @@
+// Hand-written TypeScript counterparts of the types exchanged with the Rust backend.
+// Keep these in sync with `lib/src/http.rs`; field names follow serde's camelCase renaming.
+
+export type HttpVersion = "Http10" | "Http11" | "Http2";
+
+export type HttpAuth =
+  | { type: "basic"; username: string; password: string }
+  | { type: "bearer"; token: string };
+
+export interface HttpRequest {
+  url: string;
+  method: string;
+  headers?: string[];
+  body?: string | null;
+  httpVersion?: HttpVersion | null;
+  addContentMd5?: boolean;
+  timeoutMs?: number | null;
+  auth?: HttpAuth | null;
+  verbose?: boolean;
+  followRedirects?: boolean;
+  bufferSize?: number | null;
+  uploadBufferSize?: number | null;
+  autoAccept?: boolean;
+  maxBodyBytes?: number | null;
+  correlationId?: string | null;
+  correlationHeader?: string | null;
+}
+
+export interface HttpResponse {
+  status: number;
+  version: string;
+  headers: string[];
+  body: string;
+  curlDebug?: string;
+  effectiveUrl?: string;
+}
+
+// Payload of the `ws-message-{id}` events, see `WsMessage` in `lib/src/websocket.rs`.
+export type WsMessage =
+  | { type: "text"; data: string }
+  | { type: "binary"; data: number[] };
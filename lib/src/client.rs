@@ -0,0 +1,963 @@
+//! This file contains `HttpClient`, a reusable handle for sending HTTP requests.
+//!
+//! Where `send_http_request` performs a single standalone request, `HttpClient` is the place
+//! for behavior spanning several requests, such as following paginated APIs.
+//!
+//! A client keeps one curl handle for all of its requests, so connections to the same host
+//! are kept alive and reused instead of being opened anew for every request.
+//!
+//! A client also carries defaults (proxy, credentials, timeout and headers), set through
+//! `HttpClientBuilder`, that `send_http_request_with_client` merges into every request it sends.
+//! With a `RetryPolicy`, it also resends requests the server asked to retry later, and with
+//! a history it remembers its most recent exchanges. For tests, `HttpClient::intercept`
+//! answers matching requests with a fixed response instead of sending them.
+//! `HttpClient::follow_sse_and_collect` gathers the events of a server-sent event stream.
+//! With `HttpClientBuilder::with_persistent_cookies`, cookies outlive the client in a file,
+//! and `HttpClientBuilder::with_request_transform` rewrites or rejects requests before they
+//! are sent.
+
+use crate::error::ReqlyError;
+use crate::http::{
+    collect_sse, header_name, perform_multi, perform_request, prepare_transfer, Collector, HttpAuth, HttpRequest,
+    HttpResponse,
+};
+use crate::retry::RetryPolicy;
+use crate::sse::SseEvent;
+use curl::easy::Easy2;
+use curl::multi::Multi;
+use futures::stream::{self, Stream};
+use regex::Regex;
+use std::collections::VecDeque;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use url::Url;
+
+/// A client for sending HTTP requests.
+///
+/// Clones share the same underlying curl handle and its connection cache, and each carries
+/// its own copy of the defaults set through `HttpClientBuilder`.
+#[derive(Clone)]
+pub struct HttpClient {
+    easy: Arc<Mutex<Easy2<Collector>>>,
+    http_proxy: Option<String>,
+    https_proxy: Option<String>,
+    no_proxy: Vec<String>,
+    auth: Option<HttpAuth>,
+    timeout_ms: Option<u64>,
+    headers: Vec<String>,
+    max_connections_per_host: Option<usize>,
+    retry_policy: Option<RetryPolicy>,
+    history_capacity: usize,
+    cookie_path: Option<PathBuf>,
+    transforms: Vec<RequestTransform>,
+    auto_accept_disabled: bool,
+    history: Arc<Mutex<VecDeque<(HttpRequest, HttpResponse)>>>,
+    interceptors: Arc<Mutex<Vec<(Regex, HttpResponse)>>>,
+}
+
+/// Builds an `HttpClient` with defaults for every request it sends.
+#[derive(Debug, Default)]
+pub struct HttpClientBuilder {
+    http_proxy: Option<String>,
+    https_proxy: Option<String>,
+    no_proxy: Vec<String>,
+    auth: Option<HttpAuth>,
+    timeout_ms: Option<u64>,
+    headers: Vec<String>,
+    max_connections_per_host: Option<usize>,
+    retry_policy: Option<RetryPolicy>,
+    history_capacity: usize,
+    cookie_path: Option<PathBuf>,
+    transforms: Vec<RequestTransform>,
+    auto_accept_disabled: bool,
+}
+
+/// A function applied to every request of a client, see `HttpClientBuilder::with_request_transform`.
+#[derive(Clone)]
+struct RequestTransform(Arc<dyn Fn(HttpRequest) -> Result<HttpRequest, ReqlyError> + Send + Sync>);
+
+impl fmt::Debug for RequestTransform {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("RequestTransform")
+    }
+}
+
+impl HttpClientBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sends every request through the proxy at `proxy`, e.g. `http://127.0.0.1:8080`.
+    pub fn with_proxy(mut self, proxy: &str) -> Self {
+        self.http_proxy = Some(proxy.to_string());
+        self.https_proxy = Some(proxy.to_string());
+        self
+    }
+
+    /// Sends requests to `http` URLs through the proxy at `proxy`.
+    pub fn with_http_proxy(mut self, proxy: &str) -> Self {
+        self.http_proxy = Some(proxy.to_string());
+        self
+    }
+
+    /// Sends requests to `https` URLs through the proxy at `proxy`.
+    pub fn with_https_proxy(mut self, proxy: &str) -> Self {
+        self.https_proxy = Some(proxy.to_string());
+        self
+    }
+
+    /// Sends requests to the hosts in `hosts` directly instead of through a proxy.
+    ///
+    /// `hosts` is a comma-separated list in the format of `NO_PROXY`: an entry matches its
+    /// host and all subdomains, and `*` matches every host.
+    pub fn with_no_proxy(mut self, hosts: &str) -> Self {
+        self.no_proxy = hosts
+            .split(',')
+            .map(|host| host.trim().trim_start_matches('.').to_ascii_lowercase())
+            .filter(|host| !host.is_empty())
+            .collect();
+        self
+    }
+
+    /// Uses `auth` for requests that carry no credentials of their own.
+    pub fn with_auth(mut self, auth: HttpAuth) -> Self {
+        self.auth = Some(auth);
+        self
+    }
+
+    /// Uses a timeout of `timeout_ms` for requests that set no timeout of their own.
+    pub fn with_timeout_ms(mut self, timeout_ms: u64) -> Self {
+        self.timeout_ms = Some(timeout_ms);
+        self
+    }
+
+    /// Sends requests without the `Accept` header the client adds by default, see
+    /// `HttpRequest::auto_accept`.
+    pub fn without_auto_accept(mut self) -> Self {
+        self.auto_accept_disabled = true;
+        self
+    }
+
+    /// Adds a header to every request that does not set a header of the same name.
+    pub fn with_header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push(format!("{}: {}", name, value));
+        self
+    }
+
+    /// Limits `HttpClient::send_all` to `n` simultaneous connections to the same host.
+    ///
+    /// Transfers beyond the limit wait until a connection to their host is free.
+    pub fn max_connections_per_host(mut self, n: usize) -> Self {
+        self.max_connections_per_host = Some(n);
+        self
+    }
+
+    /// Retries the responses of `send` with a status in `policy.retry_on_status`, `429` and
+    /// `503` by default.
+    pub fn with_retry_policy(mut self, policy: RetryPolicy) -> Self {
+        self.retry_policy = Some(policy);
+        self
+    }
+
+    /// Keeps the last `capacity` requests and their responses, see `HttpClient::history`.
+    pub fn with_history(mut self, capacity: usize) -> Self {
+        self.history_capacity = capacity;
+        self
+    }
+
+    /// Keeps the cookies set by responses in the Netscape cookie file at `path`.
+    ///
+    /// Cookies already in the file are sent with the first request, and the file is rewritten
+    /// after every response, so a client created later with the same path picks them up.
+    pub fn with_persistent_cookies(mut self, path: impl AsRef<Path>) -> Self {
+        self.cookie_path = Some(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// Passes every request through `f` before it is sent, after the client defaults are
+    /// merged into it.
+    ///
+    /// `f` may change the request or reject it by returning an error, usually
+    /// `ReqlyError::RequestRejected`, which is then returned instead of a response. Several
+    /// transforms run in the order they were added, each on the result of the previous one.
+    pub fn with_request_transform(
+        mut self,
+        f: impl Fn(HttpRequest) -> Result<HttpRequest, ReqlyError> + Send + Sync + 'static,
+    ) -> Self {
+        self.transforms.push(RequestTransform(Arc::new(f)));
+        self
+    }
+
+    pub fn build(self) -> HttpClient {
+        HttpClient {
+            easy: Arc::new(Mutex::new(Easy2::new(Collector::default()))),
+            http_proxy: self.http_proxy,
+            https_proxy: self.https_proxy,
+            no_proxy: self.no_proxy,
+            auth: self.auth,
+            timeout_ms: self.timeout_ms,
+            headers: self.headers,
+            max_connections_per_host: self.max_connections_per_host,
+            retry_policy: self.retry_policy,
+            history_capacity: self.history_capacity,
+            cookie_path: self.cookie_path,
+            transforms: self.transforms,
+            auto_accept_disabled: self.auto_accept_disabled,
+            history: Arc::new(Mutex::new(VecDeque::with_capacity(self.history_capacity))),
+            interceptors: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+}
+
+impl HttpClient {
+    pub fn new() -> Self {
+        HttpClientBuilder::new().build()
+    }
+
+    /// Returns a builder for a client with request defaults.
+    pub fn builder() -> HttpClientBuilder {
+        HttpClientBuilder::new()
+    }
+
+    /// Returns a client using the proxies configured in the environment.
+    ///
+    /// `HTTP_PROXY` and `HTTPS_PROXY` set the proxy for their scheme, falling back to
+    /// `ALL_PROXY`, and `NO_PROXY` lists the hosts reached directly. Each variable is also
+    /// read in lowercase when the uppercase one is not set.
+    pub fn from_environment() -> HttpClient {
+        Self::from_variables(|name| std::env::var(name).ok())
+    }
+
+    /// Returns a client using the proxies in the variables looked up by `variable`, see
+    /// `from_environment`.
+    fn from_variables(variable: impl Fn(&str) -> Option<String>) -> HttpClient {
+        let proxy_variable = |name: &str| proxy_variable(&variable, name);
+        let all_proxy = proxy_variable("ALL_PROXY");
+        let mut builder = HttpClientBuilder::new();
+        builder.http_proxy = proxy_variable("HTTP_PROXY").or_else(|| all_proxy.clone());
+        builder.https_proxy = proxy_variable("HTTPS_PROXY").or(all_proxy);
+        if let Some(no_proxy) = proxy_variable("NO_PROXY") {
+            builder = builder.with_no_proxy(&no_proxy);
+        }
+        builder.build()
+    }
+
+    /// Returns the proxy requests to `url` are sent through, if any.
+    pub fn proxy_for(&self, url: &str) -> Option<&str> {
+        let url = Url::parse(url).ok()?;
+        let host = url.host_str().unwrap_or_default().trim_matches(['[', ']']).to_ascii_lowercase();
+        let bypassed = self.no_proxy.iter().any(|entry| {
+            entry == "*" || host == *entry || host.strip_suffix(entry.as_str()).is_some_and(|rest| rest.ends_with('.'))
+        });
+        if bypassed {
+            return None;
+        }
+        match url.scheme() {
+            "https" | "wss" => self.https_proxy.as_deref(),
+            _ => self.http_proxy.as_deref(),
+        }
+    }
+
+    /// Sends an HTTP request and returns the corresponding response.
+    ///
+    /// See `send_http_request_with_client` for how the client defaults are applied.
+    pub fn send(&self, request: HttpRequest) -> Result<HttpResponse, ReqlyError> {
+        send_http_request_with_client(self, request)
+    }
+
+    /// Sends all requests concurrently and returns their responses in the same order.
+    ///
+    /// Like `send_all_multi`, the transfers are driven by one `curl::multi::Multi` handle,
+    /// which honors `HttpClientBuilder::max_connections_per_host`. The client defaults are
+    /// applied as for `send`, but the connections are not shared with `send`.
+    pub fn send_all(&self, requests: Vec<HttpRequest>) -> Result<Vec<HttpResponse>, ReqlyError> {
+        let mut multi = Multi::new();
+        if let Some(max) = self.max_connections_per_host {
+            multi.set_max_host_connections(max).map_err(|e| ReqlyError::Request(e.to_string()))?;
+        }
+        let requests_sent = requests
+            .into_iter()
+            .map(|request| self.apply_defaults(request))
+            .collect::<Result<Vec<_>, ReqlyError>>()?;
+        let mut responses: Vec<_> = requests_sent.iter().map(|request| self.intercepted(request)).collect();
+        let transfers = requests_sent
+            .iter()
+            .zip(&responses)
+            .filter(|(_, intercepted)| intercepted.is_none())
+            .map(|(request, _)| {
+                let mut easy = prepare_transfer(request.clone())?;
+                self.apply_proxy(&mut easy, &request.url)?;
+                self.apply_cookies(&mut easy)?;
+                Ok(easy)
+            })
+            .collect::<Result<Vec<_>, ReqlyError>>()?;
+        let mut performed = perform_multi(multi, transfers)?.into_iter();
+        for response in responses.iter_mut().filter(|response| response.is_none()) {
+            *response = performed.next();
+        }
+
+        let responses: Vec<HttpResponse> = responses.into_iter().flatten().collect();
+        for (request, response) in requests_sent.into_iter().zip(&responses) {
+            self.record(request, response);
+        }
+        Ok(responses)
+    }
+
+    /// Fetches `urls` with `GET` requests in parallel and returns the results in the same order.
+    ///
+    /// Each request is sent like `send`, from its own blocking task, so the client defaults,
+    /// the retry policy, interceptors and the history all apply. The tasks use a curl handle
+    /// each, whose connections are not shared with `send`. A URL that does not parse yields
+    /// `ReqlyError::InvalidUrl` in its place.
+    pub async fn batch_get(&self, urls: &[&str]) -> Vec<Result<HttpResponse, ReqlyError>> {
+        let tasks = urls.iter().map(|url| {
+            let client = HttpClient { easy: Arc::new(Mutex::new(Easy2::new(Collector::default()))), ..self.clone() };
+            let url = url.to_string();
+            async move {
+                tokio::task::spawn_blocking(move || client.send(HttpRequest::from_url(&url)?))
+                    .await
+                    .map_err(|e| ReqlyError::Request(e.to_string()))?
+            }
+        });
+        futures::future::join_all(tasks).await
+    }
+
+    /// Sends a `HEAD` request to `url` and returns the duration of the round trip, as
+    /// measured by curl.
+    ///
+    /// Any response counts, whatever its status; only a failed transfer is an error. The
+    /// client defaults apply, but the retry policy, interceptors and history do not.
+    pub fn test_connectivity(&self, url: &str) -> Result<Duration, ReqlyError> {
+        let request = self.apply_defaults(HttpRequest::head(url))?;
+        let mut easy = self.easy.lock().unwrap_or_else(|e| e.into_inner());
+        easy.reset();
+        self.apply_proxy(&mut easy, &request.url)?;
+        self.apply_cookies(&mut easy)?;
+        perform_request(&mut easy, request)?;
+        self.save_cookies(&mut easy)?;
+        easy.total_time().map_err(|e| ReqlyError::Request(e.to_string()))
+    }
+
+    /// Subscribes to the server-sent event stream of `request` and returns its events.
+    ///
+    /// Collecting stops after `max_events` events or once `timeout_ms` elapsed, then the
+    /// connection is closed; a stream the server ends earlier returns the events sent so far.
+    /// `timeout_ms` replaces any timeout of the request or the client, and `Accept:
+    /// text/event-stream` is sent unless the request sets an `Accept` header. Interceptors
+    /// and the history do not apply.
+    pub fn follow_sse_and_collect(
+        &self,
+        mut request: HttpRequest,
+        max_events: usize,
+        timeout_ms: u64,
+    ) -> Result<Vec<SseEvent>, ReqlyError> {
+        if !request.headers.iter().any(|h| header_name(h).eq_ignore_ascii_case("Accept")) {
+            request.headers.push("Accept: text/event-stream".to_string());
+        }
+        let request = self.apply_defaults(request)?;
+        let proxy = self.proxy_for(&request.url);
+        collect_sse(request, proxy, max_events, timeout_ms)
+    }
+
+    /// Answers every request whose URL matches `url_pattern` with a copy of `response`,
+    /// without sending it.
+    ///
+    /// The URL is matched as given on the request, after the client defaults are applied.
+    /// When several patterns match, the one registered first wins. Clones of a client share
+    /// its interceptors.
+    pub fn intercept(&self, url_pattern: Regex, response: HttpResponse) {
+        self.interceptors.lock().unwrap_or_else(|e| e.into_inner()).push((url_pattern, response));
+    }
+
+    /// Returns the response of the first interceptor matching `request`, if any.
+    fn intercepted(&self, request: &HttpRequest) -> Option<HttpResponse> {
+        let interceptors = self.interceptors.lock().unwrap_or_else(|e| e.into_inner());
+        interceptors
+            .iter()
+            .find(|(pattern, _)| pattern.is_match(&request.url))
+            .map(|(_, response)| response.clone())
+    }
+
+    /// Returns the most recent exchanges, oldest first, up to the capacity set with
+    /// `HttpClientBuilder::with_history`.
+    ///
+    /// Requests are recorded with the client defaults applied. Clones of a client share
+    /// its history.
+    pub fn history(&self) -> Vec<(HttpRequest, HttpResponse)> {
+        self.history.lock().unwrap_or_else(|e| e.into_inner()).iter().cloned().collect()
+    }
+
+    fn record(&self, request: HttpRequest, response: &HttpResponse) {
+        if self.history_capacity == 0 {
+            return;
+        }
+        let mut history = self.history.lock().unwrap_or_else(|e| e.into_inner());
+        if history.len() == self.history_capacity {
+            history.pop_front();
+        }
+        history.push_back((request, response.clone()));
+    }
+
+    /// Validates `request`, merges the client defaults into it and runs the request
+    /// transforms, validating the transformed request again.
+    fn apply_defaults(&self, mut request: HttpRequest) -> Result<HttpRequest, ReqlyError> {
+        request.validate()?;
+        request.timeout_ms = request.timeout_ms.or(self.timeout_ms);
+        request.auth = request.auth.or_else(|| self.auth.clone());
+        request.auto_accept |= !self.auto_accept_disabled;
+        for header in &self.headers {
+            let name = header_name(header);
+            if !request.headers.iter().any(|h| header_name(h).eq_ignore_ascii_case(name)) {
+                request.headers.push(header.clone());
+            }
+        }
+        if self.transforms.is_empty() {
+            return Ok(request);
+        }
+        for transform in &self.transforms {
+            request = (transform.0)(request)?;
+        }
+        request.validate()?;
+        Ok(request)
+    }
+
+    fn apply_proxy(&self, easy: &mut Easy2<Collector>, url: &str) -> Result<(), ReqlyError> {
+        if let Some(proxy) = self.proxy_for(url) {
+            easy.proxy(proxy).map_err(|e| ReqlyError::Request(e.to_string()))?;
+        }
+        Ok(())
+    }
+
+    /// Reads the cookies of the cookie file into `easy` and makes it write them back, see
+    /// `HttpClientBuilder::with_persistent_cookies`.
+    ///
+    /// Without a `save_cookies` call, curl writes the file when `easy` is dropped.
+    fn apply_cookies(&self, easy: &mut Easy2<Collector>) -> Result<(), ReqlyError> {
+        if let Some(path) = &self.cookie_path {
+            easy.cookie_file(path).map_err(|e| ReqlyError::Request(e.to_string()))?;
+            easy.cookie_jar(path).map_err(|e| ReqlyError::Request(e.to_string()))?;
+        }
+        Ok(())
+    }
+
+    /// Writes the cookies of `easy` to the cookie file, if the client has one.
+    fn save_cookies(&self, easy: &mut Easy2<Collector>) -> Result<(), ReqlyError> {
+        if self.cookie_path.is_some() {
+            easy.cookie_list("FLUSH").map_err(|e| ReqlyError::Request(e.to_string()))?;
+        }
+        Ok(())
+    }
+
+    /// Follows a paginated API, yielding one response per page.
+    ///
+    /// `page_extractor` is called after each response to build the request for the next page.
+    /// The stream ends when it returns `None` or after the first error.
+    pub fn paginate<F>(
+        &self,
+        request: HttpRequest,
+        page_extractor: F,
+    ) -> impl Stream<Item = Result<HttpResponse, ReqlyError>>
+    where
+        F: Fn(&HttpResponse) -> Option<HttpRequest>,
+    {
+        let client = self.clone();
+
+        stream::unfold((Some(request), page_extractor), move |(next, page_extractor)| {
+            let client = client.clone();
+
+            async move {
+                let request = next?;
+                let result = tokio::task::spawn_blocking(move || client.send(request))
+                    .await
+                    .unwrap_or_else(|e| Err(ReqlyError::Request(e.to_string())));
+                let next = match &result {
+                    Ok(response) => page_extractor(response),
+                    Err(_) => None,
+                };
+                Some((result, (next, page_extractor)))
+            }
+        })
+    }
+}
+
+/// Returns the value `variable` gives for `name`, or for its lowercase form.
+fn proxy_variable(variable: impl Fn(&str) -> Option<String>, name: &str) -> Option<String> {
+    [name.to_string(), name.to_ascii_lowercase()]
+        .iter()
+        .find_map(|name| variable(name).filter(|value| !value.is_empty()))
+}
+
+impl Default for HttpClient {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Sends an HTTP request with the defaults of `client` and returns the corresponding response.
+///
+/// Values set on the request take precedence: its timeout and credentials replace those of
+/// the client, and a client header is only added when the request has no header of that
+/// name. The proxy always comes from the client.
+///
+/// The curl handle is held for the duration of the transfer, so concurrent sends on clones
+/// of the same client are performed one after another.
+///
+/// With a retry policy, the request is resent while the policy asks for it, after sleeping
+/// for the delay it returns. The handle is not held while sleeping.
+pub fn send_http_request_with_client(client: &HttpClient, request: HttpRequest) -> Result<HttpResponse, ReqlyError> {
+    let request = client.apply_defaults(request)?;
+    if let Some(response) = client.intercepted(&request) {
+        client.record(request, &response);
+        return Ok(response);
+    }
+
+    let mut attempt = 0;
+    loop {
+        let response = {
+            let mut easy = client.easy.lock().unwrap_or_else(|e| e.into_inner());
+            // Clears the options of the previous request but keeps its open connections.
+            easy.reset();
+            client.apply_proxy(&mut easy, &request.url)?;
+            client.apply_cookies(&mut easy)?;
+            let response = perform_request(&mut easy, request.clone())?;
+            client.save_cookies(&mut easy)?;
+            response
+        };
+        let delay = client.retry_policy.as_ref().and_then(|policy| policy.retry_delay(&response, attempt));
+        match delay {
+            Some(delay) => std::thread::sleep(delay),
+            None => {
+                client.record(request, &response);
+                return Ok(response);
+            }
+        }
+        attempt += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_server::{response, TestServer};
+    use futures::StreamExt;
+
+    fn get(url: String) -> HttpRequest {
+        HttpRequest {
+            url,
+            method: "GET".to_string(),
+            ..HttpRequest::default()
+        }
+    }
+
+    /// Tests that sequential requests to the same host reuse one connection.
+    #[test]
+    fn test_client_keep_alive() {
+        let server = TestServer::start(|_| response(200, &[], "ok"));
+        let client = HttpClient::new();
+
+        for _ in 0..3 {
+            let response = client.send(get(server.url("/"))).unwrap();
+            assert_eq!(response.status, 200);
+        }
+        assert_eq!(server.requests().len(), 3);
+        assert_eq!(server.connection_count(), 1);
+    }
+
+    /// Tests that a request timeout overrides the client timeout in both directions.
+    #[test]
+    fn test_client_timeout_precedence() {
+        let server = TestServer::start(|_| {
+            std::thread::sleep(std::time::Duration::from_millis(300));
+            response(200, &[], "slow")
+        });
+
+        let short_client = HttpClient::builder().with_timeout_ms(100).build();
+        assert!(short_client.send(get(server.url("/"))).is_err());
+        let mut long_request = get(server.url("/"));
+        long_request.timeout_ms = Some(2000);
+        assert_eq!(short_client.send(long_request).unwrap().body, "slow");
+
+        let long_client = HttpClient::builder().with_timeout_ms(2000).build();
+        let mut short_request = get(server.url("/"));
+        short_request.timeout_ms = Some(100);
+        assert!(long_client.send(short_request).is_err());
+    }
+
+    /// Tests that client headers are added unless the request sets the same header.
+    #[test]
+    fn test_client_default_headers() {
+        let server = TestServer::start(|_| response(200, &[], "ok"));
+        let client = HttpClient::builder()
+            .with_header("X-Client", "reqly")
+            .with_header("Accept", "text/plain")
+            .with_auth(HttpAuth::Bearer { token: "client-token".to_string() })
+            .build();
+
+        let request = get(server.url("/")).with_header("accept", "application/json");
+        send_http_request_with_client(&client, request).unwrap();
+
+        let recorded = &server.requests()[0];
+        assert_eq!(recorded.header("X-Client"), Some("reqly"));
+        assert_eq!(recorded.header("Accept"), Some("application/json"));
+        assert_eq!(recorded.header("Authorization"), Some("Bearer client-token"));
+    }
+
+    /// Tests that `send_all` keeps to the connection limit per host.
+    #[test]
+    fn test_send_all_max_connections_per_host() {
+        let server = TestServer::start(|request| {
+            std::thread::sleep(std::time::Duration::from_millis(20));
+            response(200, &[], request.path.as_bytes())
+        });
+        let client = HttpClient::builder().max_connections_per_host(2).build();
+
+        let requests = (0..20).map(|i| get(server.url(&format!("/item/{}", i)))).collect();
+        let responses = client.send_all(requests).unwrap();
+
+        assert_eq!(responses.len(), 20);
+        for (i, response) in responses.iter().enumerate() {
+            assert_eq!(response.body, format!("/item/{}", i));
+        }
+        assert_eq!(server.requests().len(), 20);
+        assert!(server.max_active_connections() <= 2);
+    }
+
+    /// Tests that a `429` response is retried after the delay of its `Retry-After` header.
+    #[test]
+    fn test_retry_after_rate_limit() {
+        let attempts = std::sync::atomic::AtomicUsize::new(0);
+        let server = TestServer::start(move |_| {
+            if attempts.fetch_add(1, std::sync::atomic::Ordering::SeqCst) == 0 {
+                response(429, &["Retry-After: 2"], "slow down")
+            } else {
+                response(200, &[], "ok")
+            }
+        });
+        let client = HttpClient::builder()
+            .with_retry_policy(RetryPolicy::new(1, std::time::Duration::from_millis(10)))
+            .build();
+
+        let started = std::time::Instant::now();
+        let response = client.send(get(server.url("/"))).unwrap();
+        let elapsed = started.elapsed();
+
+        assert_eq!(response.status, 200);
+        assert_eq!(server.requests().len(), 2);
+        assert!(elapsed >= std::time::Duration::from_millis(1900), "retried after {:?}", elapsed);
+        assert!(elapsed < std::time::Duration::from_millis(3000), "retried after {:?}", elapsed);
+    }
+
+    /// Tests retrying on a configured status until the server answers with `200`.
+    #[test]
+    fn test_retry_on_status() {
+        let attempts = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let calls = attempts.clone();
+        let server = TestServer::start(move |_| {
+            if calls.fetch_add(1, std::sync::atomic::Ordering::SeqCst) < 2 {
+                response(429, &[], "slow down")
+            } else {
+                response(200, &[], "ok")
+            }
+        });
+        let policy = RetryPolicy::new(3, std::time::Duration::from_millis(10)).only_retry_on_status(vec![429]);
+        let client = HttpClient::builder().with_retry_policy(policy).build();
+
+        let response = client.send(get(server.url("/"))).unwrap();
+        assert_eq!(response.status, 200);
+        assert_eq!(response.body, "ok");
+        assert_eq!(attempts.load(std::sync::atomic::Ordering::SeqCst), 3);
+    }
+
+    /// Tests that the history keeps only the most recent exchanges.
+    #[test]
+    fn test_client_history() {
+        let server = TestServer::start(|request| response(200, &[], request.path.as_bytes()));
+        let client = HttpClient::builder().with_history(3).with_header("X-Client", "reqly").build();
+
+        for i in 0..5 {
+            client.send(get(server.url(&format!("/{}", i)))).unwrap();
+        }
+
+        let history = client.history();
+        assert_eq!(history.len(), 3);
+        let paths: Vec<_> = history.iter().map(|(_, response)| response.body.as_str()).collect();
+        assert_eq!(paths, ["/2", "/3", "/4"]);
+        assert_eq!(history[0].0.url, server.url("/2"));
+        assert!(history[0].0.headers.contains(&"X-Client: reqly".to_string()));
+
+        let without_history = HttpClient::new();
+        without_history.send(get(server.url("/"))).unwrap();
+        assert!(without_history.history().is_empty());
+    }
+
+    /// Tests that the proxies of the environment are picked up and used.
+    #[test]
+    fn test_client_from_environment() {
+        // The server stands in for the proxy, which receives the absolute URL as the path.
+        let proxy = TestServer::start(|_| response(200, &[], "proxied"));
+        let proxy_url = proxy.url("");
+        let client = HttpClient::from_variables(|name| match name {
+            "HTTP_PROXY" => Some(proxy_url.clone()),
+            "NO_PROXY" => Some("internal.example, .corp.example".to_string()),
+            _ => None,
+        });
+
+        assert_eq!(client.proxy_for("http://reqly.invalid/"), Some(proxy.url("").as_str()));
+        assert_eq!(client.proxy_for("http://internal.example/"), None);
+        assert_eq!(client.proxy_for("http://api.corp.example/"), None);
+        assert_eq!(client.proxy_for("http://notcorp.example/"), Some(proxy.url("").as_str()));
+        let http_only = HttpClient::builder().with_http_proxy("http://127.0.0.1:9999").build();
+        assert_eq!(http_only.proxy_for("https://reqly.invalid/"), None);
+        let fallback = HttpClient::from_variables(|name| match name {
+            "all_proxy" => Some("http://127.0.0.1:9999".to_string()),
+            "HTTPS_PROXY" => Some(String::new()),
+            "https_proxy" => Some("http://127.0.0.1:8888".to_string()),
+            _ => None,
+        });
+        assert_eq!(fallback.proxy_for("http://reqly.invalid/"), Some("http://127.0.0.1:9999"));
+        assert_eq!(fallback.proxy_for("https://reqly.invalid/"), Some("http://127.0.0.1:8888"));
+
+        let response = client.send(get("http://reqly.invalid/path".to_string())).unwrap();
+        assert_eq!(response.body, "proxied");
+        assert_eq!(proxy.requests()[0].path, "http://reqly.invalid/path");
+    }
+
+    /// Tests that cookies saved by one client are sent by a later client using the same file.
+    #[test]
+    fn test_persistent_cookies() {
+        let server = TestServer::start(|request| match request.path.as_str() {
+            "/login" => response(200, &["Set-Cookie: session=abc123; Path=/; Max-Age=3600"], "ok"),
+            _ => response(200, &[], request.header("Cookie").unwrap_or_default()),
+        });
+        let path = std::env::temp_dir().join(format!("reqly-cookies-{}.txt", uuid::Uuid::new_v4()));
+
+        let client = HttpClient::builder().with_persistent_cookies(&path).build();
+        client.send(get(server.url("/login"))).unwrap();
+        let saved = std::fs::read_to_string(&path).unwrap();
+        assert!(saved.contains("session\tabc123"), "cookie file: {}", saved);
+
+        let restarted = HttpClient::builder().with_persistent_cookies(&path).build();
+        let response = restarted.send(get(server.url("/me"))).unwrap();
+        assert_eq!(response.body, "session=abc123");
+
+        let without_cookies = HttpClient::new();
+        assert_eq!(without_cookies.send(get(server.url("/me"))).unwrap().body, "");
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    /// Tests that parallel fetches return their results in the order of the URLs.
+    #[tokio::test]
+    async fn test_batch_get() {
+        // Earlier URLs are answered later, so the transfers finish in reverse order.
+        let server = TestServer::start(|request| {
+            let delay: u64 = request.path.trim_start_matches("/delay/").parse().unwrap();
+            std::thread::sleep(std::time::Duration::from_millis(delay));
+            response(200, &[], &request.path)
+        });
+        let client = HttpClient::builder().with_header("X-Client", "reqly").build();
+        let urls = [server.url("/delay/200"), server.url("/delay/100"), server.url("/delay/0")];
+        let urls: Vec<&str> = urls.iter().map(String::as_str).collect();
+
+        let results = client.batch_get(&urls).await;
+        let bodies: Vec<_> = results
+            .into_iter()
+            .map(|result| {
+                let response = result.unwrap();
+                assert_eq!(response.status, 200);
+                response.body
+            })
+            .collect();
+        assert_eq!(bodies, ["/delay/200", "/delay/100", "/delay/0"]);
+        assert!(server.requests().iter().all(|request| request.method == "GET" && request.header("X-Client") == Some("reqly")));
+
+        let results = client.batch_get(&["not a url", urls[2]]).await;
+        assert!(matches!(results[0], Err(ReqlyError::InvalidUrl(_))));
+        assert_eq!(results[1].as_ref().unwrap().body, "/delay/0");
+    }
+
+    /// Tests that parallel fetches go through the retry policy, interceptors and history.
+    #[tokio::test]
+    async fn test_batch_get_client_config() {
+        let attempts = std::sync::atomic::AtomicUsize::new(0);
+        let server = TestServer::start(move |_| {
+            if attempts.fetch_add(1, std::sync::atomic::Ordering::SeqCst) == 0 {
+                response(503, &[], "unavailable")
+            } else {
+                response(200, &[], "ok")
+            }
+        });
+        let client = HttpClient::builder()
+            .with_retry_policy(RetryPolicy::new(1, std::time::Duration::from_millis(10)))
+            .with_history(10)
+            .build();
+        client.intercept(Regex::new(r"/mocked$").unwrap(), HttpResponse::builder().status(201).body("mocked").build());
+        let urls = [server.url("/retried"), server.url("/mocked")];
+        let urls: Vec<&str> = urls.iter().map(String::as_str).collect();
+
+        let results = client.batch_get(&urls).await;
+        let bodies: Vec<_> = results.into_iter().map(|result| result.unwrap().body).collect();
+        assert_eq!(bodies, ["ok", "mocked"]);
+        assert_eq!(server.requests().len(), 2);
+        assert!(server.requests().iter().all(|request| request.path == "/retried"));
+        assert_eq!(client.history().len(), 2);
+    }
+
+    /// Tests that transforms run in order and can reject a request before it is sent.
+    #[test]
+    fn test_request_transform() {
+        let server = TestServer::start(|request| response(200, &[], request.header("X-Stage").unwrap_or_default()));
+        let client = HttpClient::builder()
+            .with_request_transform(|request| {
+                if request.url.contains("example.com") {
+                    return Err(ReqlyError::RequestRejected(format!("{} is blocked", request.url)));
+                }
+                Ok(request.with_header("X-Stage", "first"))
+            })
+            .with_request_transform(|request| Ok(request.without_header("X-Stage").with_header("X-Stage", "second")))
+            .build();
+
+        let result = client.send(get("https://api.example.com/users".to_string()));
+        assert!(matches!(result, Err(ReqlyError::RequestRejected(reason)) if reason.contains("api.example.com")));
+        let results = client.send_all(vec![get(server.url("/")), get("http://example.com/".to_string())]);
+        assert!(matches!(results, Err(ReqlyError::RequestRejected(_))));
+        assert!(server.requests().is_empty());
+
+        assert_eq!(client.send(get(server.url("/"))).unwrap().body, "second");
+    }
+
+    /// Tests that a client sends the automatic `Accept` header unless built without it.
+    #[test]
+    fn test_client_auto_accept() {
+        let server = TestServer::start(|_| response(200, &[], "ok"));
+        HttpClient::new().send(get(server.url("/"))).unwrap();
+        HttpClient::new().send(get(server.url("/")).with_header("Accept", "text/html")).unwrap();
+        HttpClient::builder().without_auto_accept().build().send(get(server.url("/"))).unwrap();
+
+        let accept: Vec<_> = server.requests().iter().map(|request| request.header("Accept").map(str::to_string)).collect();
+        assert_eq!(accept[0].as_deref(), Some("application/json, */*;q=0.8"));
+        assert_eq!(accept[1].as_deref(), Some("text/html"));
+        assert_eq!(accept[2].as_deref(), Some("*/*"));
+    }
+
+    /// Tests the connectivity check against a running server and a closed port.
+    #[test]
+    fn test_connectivity() {
+        let server = TestServer::start(|_| response(404, &[], "not found"));
+        let client = HttpClient::new();
+
+        let round_trip = client.test_connectivity(&server.url("/health")).unwrap();
+        assert!(round_trip > Duration::ZERO && round_trip < Duration::from_secs(1));
+        assert_eq!(server.requests()[0].method, "HEAD");
+
+        let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let result = client.test_connectivity(&format!("http://127.0.0.1:{}/", port));
+        assert!(matches!(result, Err(ReqlyError::Curl { .. })));
+    }
+
+    /// Tests that intercepted requests are answered without reaching the network.
+    #[test]
+    fn test_client_intercept() {
+        let server = TestServer::start(|_| response(200, &[], "real"));
+        let client = HttpClient::new();
+        let mocked = HttpResponse::builder()
+            .status(201)
+            .header("Content-Type", "application/json")
+            .body("{\"mocked\":true}")
+            .build();
+        client.intercept(Regex::new(r"^https://api\.example\.com/.*").unwrap(), mocked.clone());
+        client.intercept(Regex::new(r"/mocked$").unwrap(), mocked);
+
+        let response = client.send(get("https://api.example.com/users/1".to_string())).unwrap();
+        assert_eq!(response.status, 201);
+        assert_eq!(response.body, "{\"mocked\":true}");
+        assert!(response.is_json());
+
+        let responses = client
+            .send_all(vec![get(server.url("/mocked")), get(server.url("/real")), get(server.url("/mocked"))])
+            .unwrap();
+        let bodies: Vec<_> = responses.iter().map(|response| response.body.as_str()).collect();
+        assert_eq!(bodies, ["{\"mocked\":true}", "real", "{\"mocked\":true}"]);
+        assert_eq!(server.requests().len(), 1);
+        assert_eq!(server.requests()[0].path, "/real");
+    }
+
+    /// Tests following a two-page API through its `Link` headers.
+    #[tokio::test]
+    async fn test_paginate() {
+        let server = TestServer::start(|request| {
+            if request.path == "/items?page=1" {
+                response(200, &["Link: </items?page=2>; rel=\"next\""], "[1, 2]")
+            } else {
+                response(200, &[], "[3]")
+            }
+        });
+        let base = server.url("");
+
+        let client = HttpClient::new();
+        let pages: Vec<_> = client
+            .paginate(get(server.url("/items?page=1")), |response| {
+                let next = response.link_relations().remove("next")?;
+                Some(get(format!("{}{}", base, next)))
+            })
+            .collect()
+            .await;
+
+        assert_eq!(pages.len(), 2);
+        assert_eq!(pages[0].as_ref().unwrap().body, "[1, 2]");
+        assert_eq!(pages[1].as_ref().unwrap().body, "[3]");
+        assert_eq!(server.requests().len(), 2);
+    }
+
+    /// Tests collecting the events of a stream the server keeps open, until the timeout.
+    #[test]
+    fn test_follow_sse_and_collect() {
+        use std::io::{BufRead, BufReader, Write};
+        use std::time::{Duration, Instant};
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/events", listener.local_addr().unwrap());
+        std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut request = Vec::new();
+            let mut line = String::new();
+            while reader.read_line(&mut line).unwrap() > 2 {
+                request.push(std::mem::take(&mut line));
+            }
+            assert!(request.iter().any(|line| line.trim_end() == "Accept: text/event-stream"));
+            stream
+                .write_all(
+                    b"HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\n\r\n\
+                      data: one\n\n\
+                      event: tick\nid: 2\ndata: two\n\n\
+                      data: three\n\n",
+                )
+                .unwrap();
+            std::thread::sleep(Duration::from_secs(3));
+        });
+
+        let client = HttpClient::new();
+        let started = Instant::now();
+        let events = client.follow_sse_and_collect(get(url), 10, 500).unwrap();
+        assert!(started.elapsed() < Duration::from_secs(2));
+        let data: Vec<&str> = events.iter().map(|event| event.data.as_str()).collect();
+        assert_eq!(data, ["one", "two", "three"]);
+        assert_eq!(events[1].event, "tick");
+        assert_eq!(events[2].id.as_deref(), Some("2"));
+    }
+
+    /// Tests that collecting stops after `max_events` events.
+    #[test]
+    fn test_follow_sse_max_events() {
+        let server = TestServer::start(|_| {
+            response(200, &["Content-Type: text/event-stream"], "data: 1\n\ndata: 2\n\ndata: 3\n\n")
+        });
+
+        let events = HttpClient::new().follow_sse_and_collect(get(server.url("/")), 2, 2000).unwrap();
+        let data: Vec<&str> = events.iter().map(|event| event.data.as_str()).collect();
+        assert_eq!(data, ["1", "2"]);
+        assert!(HttpClient::new().follow_sse_and_collect(get(server.url("/")), 0, 2000).unwrap().is_empty());
+    }
+}
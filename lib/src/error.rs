@@ -0,0 +1,103 @@
+//! This file contains the error type shared by the clients and connection managers.
+
+use thiserror::Error;
+
+/// Represents an error produced by Reqly.
+#[derive(Debug, Error)]
+pub enum ReqlyError {
+    /// The HTTP request could not be performed.
+    #[error("request failed: {0}")]
+    Request(String),
+
+    /// curl failed to perform the transfer of a request.
+    #[error("[{method} {url}] {source}")]
+    Curl {
+        method: String,
+        url: String,
+        source: curl::Error,
+    },
+
+    /// A URL could not be parsed.
+    #[error("invalid URL: {0}")]
+    InvalidUrl(String),
+
+    /// A request method is empty or otherwise unusable.
+    #[error("invalid method: {0:?}")]
+    InvalidMethod(String),
+
+    /// A request is malformed as a whole, e.g. a `HEAD` request with a body.
+    #[error("invalid request: {0}")]
+    InvalidRequest(String),
+
+    /// A request transform of an `HttpClient` refused to let the request be sent.
+    #[error("request rejected: {0}")]
+    RequestRejected(String),
+
+    /// A header could not be used as given.
+    #[error("invalid header: {0}")]
+    InvalidHeader(String),
+
+    /// A response body did not match its integrity digest.
+    #[error("integrity check failed: {0}")]
+    IntegrityError(String),
+
+    /// A response body is not in the format it was read as.
+    #[error("invalid response body: {0}")]
+    InvalidBody(String),
+
+    /// A response body reached the `max_body_bytes` limit of its request, given here.
+    #[error("response body reached the limit of {0} bytes")]
+    ResponseTooLarge(usize),
+
+    /// The operation did not complete in time.
+    #[error("operation timed out")]
+    Timeout,
+
+    /// A command queue is full and the command was not enqueued.
+    #[error("command queue is full")]
+    QueueFull,
+
+    /// An I/O operation on a socket failed.
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+
+    /// A DTLS session could not be set up or used.
+    #[error("DTLS error: {0}")]
+    Dtls(String),
+
+    /// A value could not be converted to or from JSON.
+    #[error("JSON error: {0}")]
+    Json(#[from] serde_json::Error),
+
+    /// A JSON-RPC call was answered with an error object.
+    #[error("JSON-RPC error {code}: {message}")]
+    JsonRpc {
+        code: i64,
+        message: String,
+        data: Option<serde_json::Value>,
+    },
+
+    /// No open connection has the given ID.
+    #[error("connection {0} not found")]
+    ConnectionNotFound(usize),
+
+    /// The WebSocket connection failed.
+    #[error("websocket error: {0}")]
+    WebSocket(Box<tokio_tungstenite::tungstenite::Error>),
+
+    /// The Socket.IO connection failed.
+    #[error("Socket.IO error: {0}")]
+    SocketIo(Box<socketio_rs::Error>),
+}
+
+impl From<tokio_tungstenite::tungstenite::Error> for ReqlyError {
+    fn from(error: tokio_tungstenite::tungstenite::Error) -> Self {
+        ReqlyError::WebSocket(Box::new(error))
+    }
+}
+
+impl From<socketio_rs::Error> for ReqlyError {
+    fn from(error: socketio_rs::Error) -> Self {
+        ReqlyError::SocketIo(Box::new(error))
+    }
+}
@@ -12,8 +12,21 @@
 //!
 //! This library is licensed under the [AGPL-3.0](https://choosealicense.com/licenses/agpl-3.0/).
 
+mod client;
+mod error;
 mod http;
+mod multipart;
+mod retry;
+mod sse;
 mod websocket;
 
-pub use http::{HttpRequest, HttpResponse, HttpRequestGroup, send_http_request};
-pub use websocket::{WebSocketManager, UdpManager, SocketIOManager, TcpManager};
\ No newline at end of file
+#[cfg(test)]
+mod test_server;
+
+pub use client::{send_http_request_with_client, HttpClient, HttpClientBuilder};
+pub use error::ReqlyError;
+pub use http::{HttpAuth, HttpRequest, HttpResponse, HttpResponseBuilder, HttpRequestGroup, HttpVersion, StatusClass, normalize_url, send_all_multi, send_http_request, send_http_request_async, send_http_request_streaming};
+pub use multipart::{MultipartBuilder, MultipartForm, MultipartPart};
+pub use retry::RetryPolicy;
+pub use sse::SseEvent;
+pub use websocket::{DtlsConfig, WebSocketManager, WsConnectionState, WsMessage, UdpManager, SocketIOManager, TcpManager, TcpStats};
\ No newline at end of file
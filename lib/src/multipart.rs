@@ -0,0 +1,288 @@
+//! This file contains support for building `multipart/form-data` request bodies.
+//!
+//! It defines the following main types:
+//! - `MultipartPart`: A single named part of a form, optionally carrying a filename and content type.
+//! - `MultipartBuilder`: Collects parts and serializes them into a `MultipartForm`.
+//! - `MultipartForm`: A serialized form body together with the boundary that delimits its parts.
+//!
+//! A built form is attached to an `HttpRequest` through its `multipart` field, which makes
+//! `send_http_request` send the serialized body with the matching `Content-Type` header.
+//!
+//! Parts created with `MultipartPart::from_reader` are not held in memory: their data is read
+//! from the reader while the request is sent, and the form is uploaded chunked.
+
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::io::{self, Read};
+use std::sync::{Arc, Mutex};
+use uuid::Uuid;
+
+/// Represents a single part of a multipart form.
+///
+/// A part created with `from_reader` has no `data`; its contents come from the reader.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct MultipartPart {
+    pub name: String,
+    pub filename: Option<String>,
+    pub content_type: Option<String>,
+    pub data: Vec<u8>,
+    reader: Option<PartReader>,
+}
+
+/// The reader of a streamed part, shared by the clones of a part or form.
+///
+/// Readers cannot be compared, so two are equal only if they are the same reader.
+#[derive(Clone)]
+pub(crate) struct PartReader(Arc<Mutex<Box<dyn Read + Send>>>);
+
+impl PartReader {
+    pub(crate) fn read(&self, into: &mut [u8]) -> io::Result<usize> {
+        self.0.lock().unwrap_or_else(|e| e.into_inner()).read(into)
+    }
+}
+
+impl fmt::Debug for PartReader {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("PartReader")
+    }
+}
+
+impl PartialEq for PartReader {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl Eq for PartReader {}
+
+impl Hash for PartReader {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        Arc::as_ptr(&self.0).cast::<()>().hash(state);
+    }
+}
+
+impl MultipartPart {
+    /// Returns a file part whose contents are read from `reader` while the request is sent.
+    ///
+    /// The reader is consumed by the first request sending the part; clones of the part or
+    /// of its form share it.
+    pub fn from_reader(name: &str, filename: &str, content_type: &str, reader: impl Read + Send + 'static) -> Self {
+        MultipartPart {
+            name: name.to_string(),
+            filename: Some(filename.to_string()),
+            content_type: Some(content_type.to_string()),
+            data: Vec::new(),
+            reader: Some(PartReader(Arc::new(Mutex::new(Box::new(reader))))),
+        }
+    }
+}
+
+/// Builds a `multipart/form-data` body from a list of parts.
+#[derive(Debug, Default)]
+pub struct MultipartBuilder {
+    boundary: Option<String>,
+    parts: Vec<MultipartPart>,
+}
+
+/// Represents a serialized multipart form body.
+///
+/// The contents of streamed parts are not part of `body`; each reader is listed with the
+/// offset in `body` where its contents go.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct MultipartForm {
+    boundary: String,
+    body: Vec<u8>,
+    readers: Vec<(usize, PartReader)>,
+}
+
+impl MultipartBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Uses `boundary` to delimit the parts instead of a generated one.
+    ///
+    /// This is mostly useful for snapshot tests, where the serialized body must be deterministic.
+    pub fn with_boundary(mut self, boundary: &str) -> Self {
+        self.boundary = Some(boundary.to_string());
+        self
+    }
+
+    /// Adds a plain text field.
+    pub fn text(mut self, name: &str, value: &str) -> Self {
+        self.parts.push(MultipartPart {
+            name: name.to_string(),
+            filename: None,
+            content_type: None,
+            data: value.as_bytes().to_vec(),
+            reader: None,
+        });
+        self
+    }
+
+    /// Adds a file field with the given filename and content type.
+    pub fn file(mut self, name: &str, filename: &str, content_type: &str, data: Vec<u8>) -> Self {
+        self.parts.push(MultipartPart {
+            name: name.to_string(),
+            filename: Some(filename.to_string()),
+            content_type: Some(content_type.to_string()),
+            data,
+            reader: None,
+        });
+        self
+    }
+
+    /// Adds a part, such as one created with `MultipartPart::from_reader`.
+    pub fn part(mut self, part: MultipartPart) -> Self {
+        self.parts.push(part);
+        self
+    }
+
+    /// Serializes the parts into a form body, in the order they were added.
+    ///
+    /// When no boundary was set, a random UUID-based one is generated.
+    pub fn build(self) -> MultipartForm {
+        let boundary = self
+            .boundary
+            .unwrap_or_else(|| format!("reqly-{}", Uuid::new_v4().simple()));
+
+        let mut body = Vec::new();
+        let mut readers = Vec::new();
+        for part in self.parts {
+            body.extend_from_slice(format!("--{}\r\n", boundary).as_bytes());
+            body.extend_from_slice(
+                format!("Content-Disposition: form-data; name=\"{}\"", part.name).as_bytes(),
+            );
+            if let Some(filename) = &part.filename {
+                body.extend_from_slice(format!("; filename=\"{}\"", filename).as_bytes());
+            }
+            body.extend_from_slice(b"\r\n");
+            if let Some(content_type) = &part.content_type {
+                body.extend_from_slice(format!("Content-Type: {}\r\n", content_type).as_bytes());
+            }
+            body.extend_from_slice(b"\r\n");
+            match part.reader {
+                Some(reader) => readers.push((body.len(), reader)),
+                None => body.extend_from_slice(&part.data),
+            }
+            body.extend_from_slice(b"\r\n");
+        }
+        body.extend_from_slice(format!("--{}--\r\n", boundary).as_bytes());
+
+        MultipartForm { boundary, body, readers }
+    }
+}
+
+impl MultipartForm {
+    /// Returns the boundary delimiting the parts of the form.
+    pub fn boundary(&self) -> &str {
+        &self.boundary
+    }
+
+    /// Returns the value for the `Content-Type` header of a request carrying this form.
+    pub fn content_type(&self) -> String {
+        format!("multipart/form-data; boundary={}", self.boundary)
+    }
+
+    /// Returns the serialized form body.
+    ///
+    /// For a streamed form, the contents of the parts from readers are missing.
+    pub fn body(&self) -> &[u8] {
+        &self.body
+    }
+
+    /// Returns `true` if the form has parts created with `MultipartPart::from_reader`.
+    pub fn is_streamed(&self) -> bool {
+        !self.readers.is_empty()
+    }
+
+    /// Returns the readers of the streamed parts with their offsets into `body`.
+    pub(crate) fn readers(&self) -> &[(usize, PartReader)] {
+        &self.readers
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Tests that a custom boundary is used verbatim in the serialized body.
+    #[test]
+    fn test_multipart_custom_boundary() {
+        let form = MultipartBuilder::new()
+            .with_boundary("reqly-test-boundary")
+            .text("field", "value")
+            .file("upload", "hello.txt", "text/plain", b"hello".to_vec())
+            .build();
+
+        let body = String::from_utf8(form.body().to_vec()).unwrap();
+        assert_eq!(form.boundary(), "reqly-test-boundary");
+        assert_eq!(form.content_type(), "multipart/form-data; boundary=reqly-test-boundary");
+        assert_eq!(
+            body,
+            "--reqly-test-boundary\r\n\
+             Content-Disposition: form-data; name=\"field\"\r\n\
+             \r\n\
+             value\r\n\
+             --reqly-test-boundary\r\n\
+             Content-Disposition: form-data; name=\"upload\"; filename=\"hello.txt\"\r\n\
+             Content-Type: text/plain\r\n\
+             \r\n\
+             hello\r\n\
+             --reqly-test-boundary--\r\n"
+        );
+    }
+
+    /// Tests that a part from a reader is left out of the body and read when sent.
+    #[test]
+    fn test_multipart_from_reader() {
+        let form = MultipartBuilder::new()
+            .with_boundary("b")
+            .part(MultipartPart::from_reader("upload", "data.bin", "application/octet-stream", &b"streamed"[..]))
+            .build();
+
+        assert!(form.is_streamed());
+        let body = String::from_utf8(form.body().to_vec()).unwrap();
+        assert!(!body.contains("streamed"));
+        let (offset, reader) = &form.readers()[0];
+        assert!(body[..*offset].ends_with("Content-Type: application/octet-stream\r\n\r\n"));
+        let mut data = [0; 16];
+        assert_eq!(reader.read(&mut data).unwrap(), 8);
+        assert_eq!(&data[..8], b"streamed");
+
+        assert_eq!(form.clone(), form);
+        assert!(!MultipartBuilder::new().text("a", "b").build().is_streamed());
+    }
+
+    /// Tests that parts are serialized in the order they were added, whatever their kind.
+    #[test]
+    fn test_multipart_part_order() {
+        let form = MultipartBuilder::new()
+            .with_boundary("b")
+            .part(MultipartPart::from_reader("a", "a.bin", "application/octet-stream", &b""[..]))
+            .text("b", "text")
+            .file("c", "c.txt", "text/plain", b"file".to_vec())
+            .build();
+
+        let body = String::from_utf8(form.body().to_vec()).unwrap();
+        let positions: Vec<usize> = ["name=\"a\"", "name=\"b\"", "name=\"c\"", "--b--"]
+            .iter()
+            .map(|marker| body.find(marker).unwrap())
+            .collect();
+        assert!(positions.windows(2).all(|pair| pair[0] < pair[1]), "body: {}", body);
+        assert_eq!(body.matches("--b\r\n").count(), 3);
+    }
+
+    /// Tests that a boundary is generated when none is set.
+    #[test]
+    fn test_multipart_generated_boundary() {
+        let first = MultipartBuilder::new().text("field", "value").build();
+        let second = MultipartBuilder::new().text("field", "value").build();
+
+        assert!(first.boundary().starts_with("reqly-"));
+        assert_ne!(first.boundary(), second.boundary());
+        let body = String::from_utf8(first.body().to_vec()).unwrap();
+        assert!(body.contains(&format!("--{}\r\n", first.boundary())));
+        assert!(body.ends_with(&format!("--{}--\r\n", first.boundary())));
+    }
+}
@@ -0,0 +1,101 @@
+//! This file contains `RetryPolicy`, which decides whether and when `HttpClient` resends a
+//! request that the server asked to try again later.
+//!
+//! By default, only `429 Too Many Requests` and `503 Service Unavailable` responses are
+//! retried, as both tell the client that the same request may succeed later; other status
+//! codes can be added through `RetryPolicy::also_retry_on_status`. Transfer errors are returned
+//! as is.
+
+use crate::http::HttpResponse;
+use std::time::Duration;
+
+/// How often and after what delay `HttpClient` retries a request.
+///
+/// A `Retry-After` header on the response takes precedence over `delay`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// The number of retries after the first attempt.
+    pub max_retries: u32,
+    /// The delay before a retry when the response carries no `Retry-After` header.
+    pub delay: Duration,
+    /// The status codes of the responses that are retried, `429` and `503` by default.
+    pub retry_on_status: Vec<u32>,
+}
+
+impl RetryPolicy {
+    pub fn new(max_retries: u32, delay: Duration) -> Self {
+        RetryPolicy { max_retries, delay, retry_on_status: vec![429, 503] }
+    }
+
+    /// Returns the policy retrying the responses with a status in `statuses` as well as those
+    /// already retried.
+    pub fn also_retry_on_status(mut self, statuses: Vec<u32>) -> Self {
+        for status in statuses {
+            if !self.retry_on_status.contains(&status) {
+                self.retry_on_status.push(status);
+            }
+        }
+        self
+    }
+
+    /// Returns the policy retrying the responses with a status in `statuses` only, replacing
+    /// the default `429` and `503`.
+    pub fn only_retry_on_status(mut self, statuses: Vec<u32>) -> Self {
+        self.retry_on_status = statuses;
+        self
+    }
+
+    /// Returns how long to wait before retrying after `response`, or `None` if the response
+    /// should be returned to the caller.
+    ///
+    /// `attempt` is the number of retries already made.
+    pub fn retry_delay(&self, response: &HttpResponse, attempt: u32) -> Option<Duration> {
+        if attempt >= self.max_retries || !self.retry_on_status.contains(&response.status) {
+            return None;
+        }
+        Some(response.retry_after().unwrap_or(self.delay))
+    }
+}
+
+impl Default for RetryPolicy {
+    /// Three retries, one second apart.
+    fn default() -> Self {
+        RetryPolicy::new(3, Duration::from_secs(1))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn response(status: u32) -> HttpResponse {
+        HttpResponse::builder().status(status).build()
+    }
+
+    /// Tests which responses are retried and how long the policy waits.
+    #[test]
+    fn test_retry_delay() {
+        let policy = RetryPolicy::new(2, Duration::from_millis(100));
+
+        assert_eq!(policy.retry_delay(&response(200), 0), None);
+        assert_eq!(policy.retry_delay(&response(500), 0), None);
+        assert_eq!(policy.retry_delay(&response(503), 0), Some(Duration::from_millis(100)));
+        let limited = HttpResponse::builder().status(429).header("Retry-After", "2").build();
+        assert_eq!(policy.retry_delay(&limited, 1), Some(Duration::from_secs(2)));
+        assert_eq!(policy.retry_delay(&limited, 2), None);
+    }
+
+    /// Tests that the retried status codes can be changed.
+    #[test]
+    fn test_retry_on_status() {
+        let policy = RetryPolicy::new(2, Duration::from_millis(100)).also_retry_on_status(vec![502, 503]);
+        assert_eq!(policy.retry_on_status, [429, 503, 502]);
+        assert_eq!(policy.retry_delay(&response(502), 0), Some(Duration::from_millis(100)));
+        assert_eq!(policy.retry_delay(&response(429), 0), Some(Duration::from_millis(100)));
+
+        let policy = policy.only_retry_on_status(vec![429]);
+        assert_eq!(policy.retry_delay(&response(429), 0), Some(Duration::from_millis(100)));
+        assert_eq!(policy.retry_delay(&response(503), 0), None);
+        assert_eq!(policy.retry_delay(&response(502), 0), None);
+    }
+}
@@ -0,0 +1,141 @@
+//! This file contains support for consuming server-sent events (`text/event-stream`).
+//!
+//! It defines the following main types:
+//! - `SseEvent`: A single event dispatched by the server.
+//! - `SseParser`: Splits a stream of bytes into events, following the WHATWG event stream format.
+//!
+//! `HttpClient::follow_sse_and_collect` uses the parser to collect the events of a stream.
+
+/// Represents an event received on a server-sent event stream.
+///
+/// `event` is `"message"` unless the server set another type, and `id` is the last event ID
+/// seen on the stream, which carries over to later events that do not set one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SseEvent {
+    pub event: String,
+    pub data: String,
+    pub id: Option<String>,
+    pub retry: Option<u64>,
+}
+
+/// Parses an event stream fed in chunks of any size.
+#[derive(Debug, Default)]
+pub(crate) struct SseParser {
+    line: Vec<u8>,
+    event: Option<String>,
+    data: String,
+    last_id: Option<String>,
+    retry: Option<u64>,
+}
+
+impl SseParser {
+    /// Consumes `chunk` and returns the events it completes.
+    pub(crate) fn feed(&mut self, chunk: &[u8]) -> Vec<SseEvent> {
+        let mut events = Vec::new();
+        for &byte in chunk {
+            if byte != b'\n' {
+                self.line.push(byte);
+                continue;
+            }
+            let mut line = std::mem::take(&mut self.line);
+            if line.last() == Some(&b'\r') {
+                line.pop();
+            }
+            if let Some(event) = self.process_line(&String::from_utf8_lossy(&line)) {
+                events.push(event);
+            }
+        }
+        events
+    }
+
+    /// Applies one line to the event being built, returning it when the line is blank.
+    fn process_line(&mut self, line: &str) -> Option<SseEvent> {
+        if line.is_empty() {
+            return self.dispatch();
+        }
+        if line.starts_with(':') {
+            return None;
+        }
+        let (field, value) = match line.split_once(':') {
+            Some((field, value)) => (field, value.strip_prefix(' ').unwrap_or(value)),
+            None => (line, ""),
+        };
+        match field {
+            "event" => self.event = Some(value.to_string()),
+            "data" => {
+                self.data.push_str(value);
+                self.data.push('\n');
+            }
+            "id" if !value.contains('\0') => self.last_id = Some(value.to_string()),
+            "retry" if !value.is_empty() && value.bytes().all(|b| b.is_ascii_digit()) => {
+                self.retry = value.parse().ok();
+            }
+            _ => {}
+        }
+        None
+    }
+
+    /// Finishes the event being built; an event without data is discarded.
+    fn dispatch(&mut self) -> Option<SseEvent> {
+        let event = self.event.take();
+        let retry = self.retry.take();
+        if self.data.is_empty() {
+            return None;
+        }
+        let mut data = std::mem::take(&mut self.data);
+        data.pop();
+        Some(SseEvent {
+            event: event.unwrap_or_else(|| "message".to_string()),
+            data,
+            id: self.last_id.clone(),
+            retry,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Tests the fields of an event, including multi-line data and an ID that carries over.
+    #[test]
+    fn test_sse_parser_fields() {
+        let mut parser = SseParser::default();
+        let stream = ": keep-alive\n\
+                      event: update\n\
+                      id: 7\n\
+                      retry: 3000\n\
+                      data: first line\n\
+                      data:second line\n\
+                      \n\
+                      data\n\
+                      \n\
+                      event: empty\n\
+                      \n";
+
+        let events = parser.feed(stream.as_bytes());
+        assert_eq!(
+            events,
+            vec![
+                SseEvent {
+                    event: "update".to_string(),
+                    data: "first line\nsecond line".to_string(),
+                    id: Some("7".to_string()),
+                    retry: Some(3000),
+                },
+                SseEvent { event: "message".to_string(), data: String::new(), id: Some("7".to_string()), retry: None },
+            ]
+        );
+    }
+
+    /// Tests that events split across chunks and CRLF line endings are parsed.
+    #[test]
+    fn test_sse_parser_chunks() {
+        let mut parser = SseParser::default();
+        assert!(parser.feed(b"data: hel").is_empty());
+        assert!(parser.feed(b"lo\r\n").is_empty());
+        let events = parser.feed(b"\r\ndata: world\n\n");
+        let data: Vec<&str> = events.iter().map(|event| event.data.as_str()).collect();
+        assert_eq!(data, ["hello", "world"]);
+    }
+}
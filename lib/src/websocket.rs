@@ -1,74 +1,697 @@
+use crate::error::ReqlyError;
 use log::{debug, info};
-use serde_json::json;
-use tokio_tungstenite::{connect_async, tungstenite::protocol::Message, MaybeTlsStream, WebSocketStream};
-use tokio::sync::{mpsc, Mutex};
-use futures::{FutureExt, SinkExt, StreamExt};
-use std::collections::HashMap;
-use std::sync::Arc;
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::handshake::client::Request;
+use tokio_tungstenite::tungstenite::http::{HeaderMap, HeaderName, HeaderValue, Uri};
+use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+use tokio_tungstenite::tungstenite::protocol::CloseFrame;
+use tokio_tungstenite::tungstenite::error::UrlError;
+use tokio_tungstenite::{client_async_tls, tungstenite::protocol::Message, MaybeTlsStream, WebSocketStream};
+use tokio::sync::{mpsc, oneshot, watch, Mutex, Notify};
+use futures::stream::{SplitSink, SplitStream};
+use futures::{SinkExt, StreamExt};
+use serde::de::DeserializeOwned;
+use socketio_rs::Payload;
+use serde::{Deserialize, Serialize};
+use openssl::ssl::{ErrorCode, HandshakeError, Ssl, SslContext, SslFiletype, SslMethod, SslStream, SslVerifyMode};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::hash::{Hash, Hasher};
+use std::io::{self, Read, Write};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex as StdMutex, Weak};
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use std::time::{SystemTime, UNIX_EPOCH};
 use url::Url;
 use std::error::Error;
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
 use tokio::net::TcpStream;
-use tokio::time::{sleep, Duration};
 use tokio::net::TcpListener;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::UdpSocket;
-use serde::{Deserialize, Serialize};
-use socketio_rs::{ClientBuilder, Payload};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader, ReadBuf};
+use tokio::net::{lookup_host, UdpSocket};
+use tokio::task::AbortHandle;
+use tokio::time::{sleep_until, Duration, Instant};
+
+type WebSocketConnection = WebSocketStream<MaybeTlsStream<BatchedStream<TcpStream>>>;
+
+/// The socket under a WebSocket connection, which holds writes back while `batching` is set.
+///
+/// tungstenite writes and flushes every frame as it is queued; buffering them here and
+/// ignoring those flushes lets `WebSocketManager::send_many` write a whole burst at once.
+struct BatchedStream<S> {
+    inner: S,
+    buffer: Vec<u8>,
+    batching: Arc<AtomicBool>,
+}
+
+impl<S: AsyncWrite + Unpin> BatchedStream<S> {
+    fn new(inner: S, batching: Arc<AtomicBool>) -> Self {
+        BatchedStream { inner, buffer: Vec::new(), batching }
+    }
+
+    /// Writes out whatever was buffered while batching.
+    fn poll_drain(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while !self.buffer.is_empty() {
+            let written = ready!(Pin::new(&mut self.inner).poll_write(cx, &self.buffer))?;
+            if written == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.buffer.drain(..written);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for BatchedStream<S> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for BatchedStream<S> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        if self.batching.load(Ordering::SeqCst) {
+            self.buffer.extend_from_slice(buf);
+            return Poll::Ready(Ok(buf.len()));
+        }
+        ready!(self.poll_drain(cx))?;
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if self.batching.load(Ordering::SeqCst) {
+            return Poll::Ready(Ok(()));
+        }
+        ready!(self.poll_drain(cx))?;
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        ready!(self.poll_drain(cx))?;
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// Opens the TCP connection for `request`, wrapped in a `BatchedStream` controlled by
+/// `batching`, and performs the WebSocket handshake over it (with TLS for `wss`).
+async fn connect_batched(request: Request, batching: Arc<AtomicBool>) -> Result<WebSocketConnection, tokio_tungstenite::tungstenite::Error> {
+    let uri = request.uri();
+    let host = uri.host().ok_or(tokio_tungstenite::tungstenite::Error::Url(UrlError::NoHostName))?;
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let port = uri
+        .port_u16()
+        .or_else(|| match uri.scheme_str() {
+            Some("wss") => Some(443),
+            Some("ws") => Some(80),
+            _ => None,
+        })
+        .ok_or(tokio_tungstenite::tungstenite::Error::Url(UrlError::UnsupportedUrlScheme))?;
+    let socket = TcpStream::connect((host, port)).await?;
+    let (ws_stream, _) = client_async_tls(request, BatchedStream::new(socket, batching)).await?;
+    Ok(ws_stream)
+}
+
+/// A WebSocket connection, read by its own background task, see `read_connection`.
+///
+/// The task owns the stream and hands the messages it reads over through `inbound`, so
+/// sending on a connection never waits for a read, and the end of the stream is noticed even
+/// when nobody calls `WebSocketManager::receive_message`. `reader` aborts the task, and
+/// `ended` is set once it saw the end of the stream.
+///
+/// `pong` holds the payload of the ping sent by `WebSocketManager::measure_latency` and is
+/// answered by the task when it sees the pong. Likewise, `rpc_waiters` holds the IDs of the
+/// JSON-RPC calls awaiting their response.
+///
+/// `uri` and `headers` are those of the handshake request, kept to reconnect the connection.
+/// `batching` is the flag of the connection's `BatchedStream`.
+struct WsConnection {
+    uri: Uri,
+    headers: HeaderMap,
+    batching: Arc<AtomicBool>,
+    sink: Mutex<SplitSink<WebSocketConnection, Message>>,
+    inbound: Inbound,
+    reader: StdMutex<Option<AbortHandle>>,
+    ended: watch::Sender<bool>,
+    pong: StdMutex<Option<(Vec<u8>, oneshot::Sender<Instant>)>>,
+    next_rpc_id: AtomicU64,
+    rpc_waiters: StdMutex<HashMap<u64, oneshot::Sender<serde_json::Value>>>,
+}
+
+impl WsConnection {
+    fn new(uri: Uri, headers: HeaderMap, batching: Arc<AtomicBool>, sink: SplitSink<WebSocketConnection, Message>) -> Self {
+        WsConnection {
+            uri,
+            headers,
+            batching,
+            sink: Mutex::new(sink),
+            inbound: Inbound::default(),
+            reader: StdMutex::new(None),
+            ended: watch::channel(false).0,
+            pong: StdMutex::new(None),
+            next_rpc_id: AtomicU64::new(1),
+            rpc_waiters: StdMutex::new(HashMap::new()),
+        }
+    }
+
+    /// Returns a handshake request for the same URL and headers as the connection's.
+    fn handshake_request(&self) -> Result<Request, ReqlyError> {
+        let mut request = self.uri.clone().into_client_request()?;
+        *request.headers_mut() = self.headers.clone();
+        Ok(request)
+    }
+
+    /// Hands `text` to the `json_rpc_call` awaiting it if it is the response to that call,
+    /// returning `true` in that case.
+    fn rpc_response_received(&self, text: &str) -> bool {
+        let mut waiters = self.rpc_waiters.lock().unwrap_or_else(|e| e.into_inner());
+        if waiters.is_empty() {
+            return false;
+        }
+        let Ok(response) = serde_json::from_str::<serde_json::Value>(text) else {
+            return false;
+        };
+        let is_response = response.get("jsonrpc").and_then(serde_json::Value::as_str) == Some("2.0")
+            && (response.get("result").is_some() || response.get("error").is_some());
+        let waiter = response
+            .get("id")
+            .and_then(serde_json::Value::as_u64)
+            .filter(|_| is_response)
+            .and_then(|id| waiters.remove(&id));
+        match waiter {
+            Some(waiter) => {
+                let _ = waiter.send(response);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Reports the arrival of a pong to `measure_latency` if it answers its ping.
+    fn pong_received(&self, payload: &[u8]) {
+        let mut pong = self.pong.lock().unwrap_or_else(|e| e.into_inner());
+        if pong.as_ref().is_some_and(|(expected, _)| expected == payload) {
+            if let Some((_, waiter)) = pong.take() {
+                let _ = waiter.send(Instant::now());
+            }
+        }
+    }
+
+    /// Waits for the reader task to hand over a pong or JSON-RPC response on `reply`, failing
+    /// with tungstenite's `ConnectionClosed` if the stream ends first.
+    async fn wait_for_reply<T>(&self, reply: oneshot::Receiver<T>) -> Result<T, ReqlyError> {
+        let mut ended = self.ended.subscribe();
+        tokio::select! {
+            Ok(value) = reply => Ok(value),
+            _ = ended.wait_for(|ended| *ended) => {
+                Err(ReqlyError::from(tokio_tungstenite::tungstenite::Error::ConnectionClosed))
+            }
+        }
+    }
+}
+
+/// The messages read from a connection, waiting for `WebSocketManager::receive_message`.
+///
+/// The queue ends with `Ok(None)` once the stream ended, possibly after the read error that
+/// ended it. The reader task waits while `cap` messages are queued, see
+/// `WebSocketManager::set_receive_buffer_cap`; by default the queue is unbounded.
+struct Inbound {
+    queue: StdMutex<VecDeque<Result<Option<WsMessage>, ReqlyError>>>,
+    cap: AtomicUsize,
+    pushed: Notify,
+    taken: Notify,
+}
+
+impl Default for Inbound {
+    fn default() -> Self {
+        Inbound {
+            queue: StdMutex::new(VecDeque::new()),
+            cap: AtomicUsize::new(usize::MAX),
+            pushed: Notify::new(),
+            taken: Notify::new(),
+        }
+    }
+}
+
+impl Inbound {
+    #[cfg(test)]
+    fn len(&self) -> usize {
+        self.queue.lock().unwrap_or_else(|e| e.into_inner()).len()
+    }
+
+    fn set_cap(&self, cap: usize) {
+        self.cap.store(cap, Ordering::SeqCst);
+        self.taken.notify_waiters();
+    }
+
+    /// Queues `message`, first waiting until fewer than `cap` messages are queued.
+    async fn push(&self, message: WsMessage) {
+        let mut message = Some(message);
+        loop {
+            let taken = self.taken.notified();
+            tokio::pin!(taken);
+            taken.as_mut().enable();
+            {
+                let mut queue = self.queue.lock().unwrap_or_else(|e| e.into_inner());
+                if queue.len() < self.cap.load(Ordering::SeqCst) {
+                    queue.push_back(Ok(message.take()));
+                }
+            }
+            if message.is_none() {
+                self.pushed.notify_waiters();
+                return;
+            }
+            taken.await;
+        }
+    }
+
+    /// Marks the end of the stream, after `error` if it ended with one, regardless of `cap`.
+    fn finish(&self, error: Option<ReqlyError>) {
+        let mut queue = self.queue.lock().unwrap_or_else(|e| e.into_inner());
+        queue.extend(error.map(Err));
+        queue.push_back(Ok(None));
+        drop(queue);
+        self.pushed.notify_waiters();
+    }
+
+    /// Returns `true` if messages or an error are queued before the end of the stream.
+    fn has_unread(&self) -> bool {
+        self.queue
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .front()
+            .is_some_and(|item| !matches!(item, Ok(None)))
+    }
+
+    /// Takes the next message, waiting for one to arrive; the end of the stream stays queued.
+    async fn pop(&self) -> Result<Option<WsMessage>, ReqlyError> {
+        loop {
+            let pushed = self.pushed.notified();
+            tokio::pin!(pushed);
+            pushed.as_mut().enable();
+            let item = {
+                let mut queue = self.queue.lock().unwrap_or_else(|e| e.into_inner());
+                match queue.front() {
+                    Some(Ok(None)) => return Ok(None),
+                    Some(_) => queue.pop_front(),
+                    None => None,
+                }
+            };
+            if let Some(item) = item {
+                self.taken.notify_waiters();
+                return item;
+            }
+            pushed.await;
+        }
+    }
+}
+
+/// Reads the next text or binary message, skipping control frames and the responses to
+/// JSON-RPC calls; `None` means the peer closed the connection.
+async fn next_message(conn: &WsConnection, stream: &mut SplitStream<WebSocketConnection>) -> Result<Option<WsMessage>, ReqlyError> {
+    loop {
+        match stream.next().await {
+            Some(Ok(Message::Text(text))) if !conn.rpc_response_received(&text) => return Ok(Some(WsMessage::Text(text))),
+            Some(Ok(Message::Text(_))) => continue,
+            Some(Ok(Message::Binary(data))) => return Ok(Some(WsMessage::Binary(data))),
+            Some(Ok(Message::Close(_))) | None => return Ok(None),
+            Some(Ok(Message::Pong(payload))) => conn.pong_received(&payload),
+            Some(Ok(_)) => continue,
+            Some(Err(e)) => return Err(ReqlyError::from(e)),
+        }
+    }
+}
 
-type WebSocketConnection = WebSocketStream<MaybeTlsStream<TcpStream>>;
-type AckCallback = fn(serde_json::Value);
+/// Reads a connection of a `WebSocketManager` until its stream ends, queueing the messages
+/// that are neither duplicates, held back for ordered delivery nor routed to a subscriber.
+///
+/// The manager is only referenced while a message is handled, so dropping it is not delayed
+/// by a connection waiting for messages; its connections are then aborted.
+async fn read_connection(
+    shared: Weak<WsShared>,
+    connection_id: usize,
+    conn: Arc<WsConnection>,
+    mut stream: SplitStream<WebSocketConnection>,
+) {
+    loop {
+        let next = next_message(&conn, &mut stream).await;
+        let Some(shared) = shared.upgrade() else {
+            return;
+        };
+        match next {
+            Ok(Some(message)) => {
+                let delivered = shared.received(connection_id, message).await;
+                drop(shared);
+                for message in delivered {
+                    conn.inbound.push(message).await;
+                }
+            }
+            Ok(None) => return shared.connection_ended(connection_id, &conn, None).await,
+            Err(e) => return shared.connection_ended(connection_id, &conn, Some(e)).await,
+        }
+    }
+}
 
 pub struct TcpManager {
     sender: mpsc::Sender<TcpCommand>,
-    connections: Arc<Mutex<Vec<TcpStream>>>,
+    connections: Arc<Mutex<HashMap<usize, Arc<TcpConnection>>>>,
+    next_id: AtomicUsize,
+    shutdown: watch::Sender<bool>, // set once by `shutdown`, ends the `listen` loops
+}
+
+/// A connection of a `TcpManager`, split into halves locked separately like `WsConnection`.
+///
+/// The map of connections is only locked to look a connection up, so a read waiting for data
+/// never blocks other connections, and sends or `shutdown_write` on the same connection.
+struct TcpConnection {
+    reader: Mutex<TcpReader>,
+    writer: Mutex<OwnedWriteHalf>,
+    stats: StdMutex<TcpStats>,
+}
+
+/// The read half of a `TcpConnection`.
+///
+/// Reads go through a buffer so that `read_line` can split lines, and `partial_line` keeps
+/// the start of a line whose read timed out until the rest arrives.
+struct TcpReader {
+    stream: BufReader<OwnedReadHalf>,
+    partial_line: Vec<u8>,
+}
+
+/// Traffic counters of a `TcpManager` connection, covering `send_message` and `receive_message`.
+///
+/// A `receive_message` call returning the empty chunk that signals EOF is not counted.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TcpStats {
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    pub messages_sent: u64,
+    pub messages_received: u64,
+}
+
+impl TcpConnection {
+    fn new(stream: TcpStream) -> Arc<Self> {
+        let (read, write) = stream.into_split();
+        Arc::new(TcpConnection {
+            reader: Mutex::new(TcpReader { stream: BufReader::new(read), partial_line: Vec::new() }),
+            writer: Mutex::new(write),
+            stats: StdMutex::new(TcpStats::default()),
+        })
+    }
+
+    fn update_stats(&self, update: impl FnOnce(&mut TcpStats)) {
+        update(&mut self.stats.lock().unwrap_or_else(|e| e.into_inner()));
+    }
+}
+
+impl TcpReader {
+    /// Fills `buf` completely, starting with the bytes left over by `read_line`.
+    async fn read_exact(&mut self, buf: &mut [u8]) -> io::Result<()> {
+        let leftover = self.partial_line.len().min(buf.len());
+        buf[..leftover].copy_from_slice(&self.partial_line[..leftover]);
+        self.partial_line.drain(..leftover);
+        self.stream.read_exact(&mut buf[leftover..]).await?;
+        Ok(())
+    }
 }
 
+/// The largest payload `TcpManager::read_framed` accepts, so a corrupt length cannot make it
+/// allocate gigabytes.
+const MAX_FRAME_LEN: usize = 16 * 1024 * 1024;
+
 enum TcpCommand {
-    SendMessage(usize, Vec<u8>),
     Close(usize),
+    /// Answered once every command queued before it was handled.
+    Drain(oneshot::Sender<()>),
 }
 
 pub struct UdpManager {
-    socket: UdpSocket,
+    socket: Arc<UdpSocket>,
+    dtls: StdMutex<Option<DtlsSession>>,
+    #[cfg(test)]
+    drop_rate: StdMutex<f64>,
+}
+
+/// The certificates used for a DTLS session, given as paths to PEM files.
+///
+/// Without `ca_cert`, the peer certificate is verified against the system trust store.
+/// `client_cert` and `client_key` are only needed for servers requesting client authentication.
+#[derive(Debug, Clone, Default)]
+pub struct DtlsConfig {
+    pub ca_cert: Option<PathBuf>,
+    pub client_cert: Option<PathBuf>,
+    pub client_key: Option<PathBuf>,
+}
+
+/// The DTLS session of a `UdpManager` with its peer.
+struct DtlsSession {
+    peer: SocketAddr,
+    stream: SslStream<DtlsChannel>,
+}
+
+/// Carries DTLS records over the connected socket of a `UdpManager`, one datagram per record.
+///
+/// Reads and writes never block; they fail with `WouldBlock` until the socket is ready.
+#[derive(Debug)]
+struct DtlsChannel {
+    socket: Arc<UdpSocket>,
+}
+
+impl Read for DtlsChannel {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.socket.try_recv(buf)
+    }
+}
+
+impl Write for DtlsChannel {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.socket.try_send(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// The largest datagram sent during a DTLS session, below the usual Ethernet MTU.
+const DTLS_MTU: u32 = 1200;
+const DTLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The largest payload a UDP datagram can carry.
+const MAX_DATAGRAM_LEN: usize = 65_535;
+
+impl DtlsConfig {
+    fn ssl(&self) -> Result<Ssl, openssl::error::ErrorStack> {
+        let mut context = SslContext::builder(SslMethod::dtls())?;
+        match &self.ca_cert {
+            Some(path) => context.set_ca_file(path)?,
+            None => context.set_default_verify_paths()?,
+        }
+        context.set_verify(SslVerifyMode::PEER);
+        if let Some(path) = &self.client_cert {
+            context.set_certificate_chain_file(path)?;
+        }
+        if let Some(path) = &self.client_key {
+            context.set_private_key_file(path, SslFiletype::PEM)?;
+        }
+
+        let mut ssl = Ssl::new(&context.build())?;
+        ssl.set_mtu(DTLS_MTU)?;
+        Ok(ssl)
+    }
 }
 
 pub struct SocketIOManager {
     socket: socketio_rs::Client,
+    middleware: StdMutex<Vec<Middleware>>,
 }
 
+/// Rewrites the event name and payload of an emit, see `SocketIOManager::register_middleware`.
+type Middleware = Box<dyn Fn(String, Payload) -> (String, Payload) + Send>;
+
 pub struct WebSocketManager {
     sender: mpsc::Sender<WebSocketCommand>,
-    connections: Arc<Mutex<Vec<WebSocketConnection>>>, // shared state across tasks
+    shared: Arc<WsShared>,
+}
+
+/// The state of a `WebSocketManager`, shared with the tasks reading its connections and
+/// handling its commands.
+struct WsShared {
+    connections: Mutex<HashMap<usize, Arc<WsConnection>>>,
+    ended: Mutex<HashMap<usize, Arc<WsConnection>>>, // closed by the peer, with messages left to receive
+    states: Mutex<HashMap<usize, WsConnectionState>>, // one entry per connection, same keys
+    next_id: AtomicUsize, // IDs are never reused, so stale IDs cannot alias new connections
+    rate_limits: Mutex<HashMap<usize, SendRateLimit>>,
+    idle_timeouts: Mutex<HashMap<usize, IdleTimeout>>,
+    error_handler: StdMutex<Option<ErrorHandler>>,
+    labels: StdMutex<HashMap<usize, String>>,
+    reconnect_handlers: StdMutex<HashMap<usize, ReconnectHandler>>,
+    subscriptions: StdMutex<Subscriptions>,
+    ordered: StdMutex<HashMap<usize, OrderedDelivery>>,
+    deduplication: StdMutex<HashMap<usize, Deduplication>>,
+}
+
+impl Drop for WsShared {
+    /// Stops reading the connections still open, which closes their sockets.
+    fn drop(&mut self) {
+        for conn in self.connections.get_mut().values() {
+            if let Some(reader) = conn.reader.lock().unwrap_or_else(|e| e.into_inner()).take() {
+                reader.abort();
+            }
+        }
+    }
+}
+
+/// The hashes of the last messages received on a connection, see
+/// `WebSocketManager::enable_deduplication`.
+#[derive(Debug)]
+struct Deduplication {
+    window_size: usize,
+    recent: VecDeque<u64>,
+}
+
+impl Deduplication {
+    /// Returns `true` if `message` is among the last `window_size` messages, and remembers it
+    /// otherwise.
+    fn is_duplicate(&mut self, message: &WsMessage) -> bool {
+        let mut hasher = DefaultHasher::new();
+        message.hash(&mut hasher);
+        let hash = hasher.finish();
+        if self.recent.contains(&hash) {
+            return true;
+        }
+        if self.recent.len() == self.window_size {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(hash);
+        false
+    }
+}
+
+/// The sequence numbers of a connection with ordered delivery, see
+/// `WebSocketManager::enable_ordered_delivery`.
+///
+/// `held` keeps the payloads that arrived ahead of `next_receive`.
+#[derive(Debug, Default)]
+struct OrderedDelivery {
+    next_send: u64,
+    next_receive: u64,
+    held: BTreeMap<u64, String>,
+}
+
+/// The envelope of a text message sent with ordered delivery.
+#[derive(Deserialize)]
+struct SequencedMessage {
+    seq: u64,
+    payload: serde_json::Value,
+}
+
+/// Returns the `[ws:label] ` prefix for the log messages of a connection, or an empty string
+/// if it has no label, see `WebSocketManager::set_label`.
+fn log_prefix(labels: &StdMutex<HashMap<usize, String>>, connection_id: usize) -> String {
+    labels
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .get(&connection_id)
+        .map(|label| format!("[ws:{}] ", label))
+        .unwrap_or_default()
+}
+
+/// Receives the errors of connections that occur in the background, see
+/// `WebSocketManager::set_error_handler`.
+///
+/// Shared so that it can be called after the handler is unlocked.
+type ErrorHandler = Arc<dyn Fn(usize, &ReqlyError) + Send + Sync>;
+
+/// Receives the ID of the connection replacing one closed by the peer, see
+/// `WebSocketManager::on_reconnect`.
+type ReconnectHandler = Box<dyn Fn(usize) + Send>;
+
+/// Receives the text messages of a topic, see `WebSocketManager::subscribe`.
+///
+/// Shared so that it can be called after the subscriptions are unlocked.
+type Subscriber = Arc<dyn Fn(String) + Send + Sync>;
+
+/// The subscribers of each connection with their topics.
+type Subscriptions = HashMap<usize, Vec<(String, Subscriber)>>;
+
+/// Closes a connection once no message was sent or received on it for `timeout`.
+struct IdleTimeout {
+    timeout: Duration,
+    deadline: Instant,
+}
+
+/// Spaces outgoing messages of a connection so at most one is sent per `interval`.
+struct SendRateLimit {
+    interval: Duration,
+    next_send: Instant,
+}
+
+/// The lifecycle state of a WebSocket connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WsConnectionState {
+    Connecting,
+    Open,
+    Closing,
+    Closed,
+}
+
+/// A data message received on a WebSocket connection.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(tag = "type", content = "data", rename_all = "camelCase")]
+pub enum WsMessage {
+    Text(String),
+    Binary(Vec<u8>),
 }
 
+/// The number of commands a `WebSocketManager` queues before senders have to wait.
+const COMMAND_QUEUE_CAPACITY: usize = 32;
+
+/// How long closing a connection waits for the peer to answer the close frame.
+const CLOSE_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long `WebSocketManager::shutdown_all` waits for all connections to close, and
+/// `TcpManager::shutdown` to shut its connections down.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long `WebSocketManager::measure_latency` waits for the pong.
+const PONG_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How many payloads ordered delivery holds back before it gives up on the missing ones.
+const MAX_HELD_MESSAGES: usize = 64;
+
 enum WebSocketCommand {
-    SendMessage(usize, String),
-    Close(usize),
+    /// Closes a connection with an optional close frame and reports on the sender once the
+    /// close handshake is done.
+    Close(usize, Option<CloseFrame<'static>>, oneshot::Sender<()>),
 }
 
 impl TcpManager {
     pub fn new() -> Self {
         let (sender, mut receiver) = mpsc::channel(32);
-        let connections = Arc::new(Mutex::new(Vec::new()));
+        let connections = Arc::new(Mutex::new(HashMap::new()));
 
-        let manager = TcpManager { sender, connections: connections.clone() };
+        let manager = TcpManager {
+            sender,
+            connections: connections.clone(),
+            next_id: AtomicUsize::new(0),
+            shutdown: watch::channel(false).0,
+        };
 
         tokio::spawn(async move {
             while let Some(command) = receiver.recv().await {
                 match command {
-                    TcpCommand::SendMessage(id, msg) => {
-                        if let Some(conn) = connections.lock().await.get_mut(id) {
-                            let _ = conn.write_all(&msg).await;
-                            info!("Sent message to connection {}", id);
-                        }
-                    }
                     TcpCommand::Close(id) => {
                         let mut conns = connections.lock().await;
-                        if id < conns.len() {
-                            conns.remove(id);
+                        if conns.remove(&id).is_some() {
                             info!("Closed connection {}", id);
                         }
                     }
+                    TcpCommand::Drain(done) => {
+                        let _ = done.send(());
+                    }
                 }
             }
         });
@@ -77,191 +700,2820 @@ impl TcpManager {
         manager
     }
 
+    /// Accepts connections on `addr` until `shutdown` is called, then returns `Ok(())`.
     pub async fn listen(&self, addr: &str) -> Result<(), Box<dyn Error>> {
+        let mut shutdown = self.shutdown.subscribe();
+        if *shutdown.borrow_and_update() {
+            return Ok(());
+        }
         let listener = TcpListener::bind(addr).await?;
 
         loop {
-            let (stream, _) = listener.accept().await?;
-            let mut conns = self.connections.lock().await;
-            let id = conns.len();
-            conns.push(stream);
+            let (stream, _) = tokio::select! {
+                accepted = listener.accept() => accepted?,
+                _ = shutdown.changed() => {
+                    info!("Stopped listening on {}", addr);
+                    return Ok(());
+                }
+            };
+            let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+            self.connections.lock().await.insert(id, TcpConnection::new(stream));
             info!("New connection accepted, id {}", id);
         }
     }
 
-    pub async fn send_message(&self, connection_id: usize, message: Vec<u8>) -> Result<(), Box<dyn Error>> {
-        let mut conns = self.connections.lock().await;
-        if let Some(conn) = conns.get_mut(connection_id) {
-            conn.write_all(&message).await?;
-        }
-        Ok(())
+    pub async fn connect(&self, addr: &str) -> Result<usize, ReqlyError> {
+        let stream = TcpStream::connect(addr).await?;
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        self.connections.lock().await.insert(id, TcpConnection::new(stream));
+        info!("Connected to {}, id {}", addr, id);
+        Ok(id)
     }
 
-    pub async fn close_connection(&self, connection_id: usize) {
-        let _ = self.sender.send(TcpCommand::Close(connection_id)).await;
+    /// Connects like `connect`, giving up with `ReqlyError::Timeout` if the connection is not
+    /// established within `timeout_ms`.
+    pub async fn connect_with_timeout(&self, addr: &str, timeout_ms: u64) -> Result<usize, ReqlyError> {
+        tokio::time::timeout(Duration::from_millis(timeout_ms), self.connect(addr))
+            .await
+            .map_err(|_| ReqlyError::Timeout)?
     }
-}
 
-impl UdpManager {
-    pub async fn new(addr: &str) -> Result<Self, Box<dyn Error>> {
-        let socket = UdpSocket::bind(addr).await?;
-        info!("UDP Manager created");
-        Ok(UdpManager { socket })
+    pub async fn send_message(&self, connection_id: usize, message: Vec<u8>) -> Result<(), Box<dyn Error>> {
+        if let Some(conn) = self.connection(connection_id).await {
+            conn.writer.lock().await.write_all(&message).await?;
+            conn.update_stats(|stats| {
+                stats.bytes_sent += message.len() as u64;
+                stats.messages_sent += 1;
+            });
+        }
+        Ok(())
     }
 
-    pub async fn send_message(&self, message: Vec<u8>, addr: &str) -> Result<(), Box<dyn Error>> {
-        self.socket.send_to(&message, addr).await?;
-        debug!("Sent UDP message to {}", addr);
-        Ok(())
+    /// Reads the next chunk of data from a connection; an empty vector means the peer closed it.
+    pub async fn receive_message(&self, connection_id: usize) -> Result<Vec<u8>, ReqlyError> {
+        let conn = self.connection(connection_id).await.ok_or(ReqlyError::ConnectionNotFound(connection_id))?;
+        let mut reader = conn.reader.lock().await;
+        let buffer = if reader.partial_line.is_empty() {
+            let mut buffer = vec![0; 1024];
+            let len = reader.stream.read(&mut buffer).await?;
+            buffer.truncate(len);
+            debug!("Received {} bytes from connection {}", len, connection_id);
+            buffer
+        } else {
+            std::mem::take(&mut reader.partial_line)
+        };
+        if !buffer.is_empty() {
+            conn.update_stats(|stats| {
+                stats.bytes_received += buffer.len() as u64;
+                stats.messages_received += 1;
+            });
+        }
+        Ok(buffer)
     }
 
-    pub async fn receive_message(&self) -> Result<(Vec<u8>, String), Box<dyn Error>> {
-        let mut buffer = vec![0; 1024];
-        let (len, addr) = self.socket.recv_from(&mut buffer).await?;
-        buffer.truncate(len);
-        debug!("Received UDP message from {}", addr);
-        Ok((buffer, addr.to_string()))
+    /// Returns the traffic counters of a connection, or `None` if there is no such connection.
+    pub async fn stats(&self, connection_id: usize) -> Option<TcpStats> {
+        let conn = self.connection(connection_id).await?;
+        let stats = *conn.stats.lock().unwrap_or_else(|e| e.into_inner());
+        Some(stats)
     }
-}
 
-impl WebSocketManager {
-    pub fn new() -> Self {
-        let (sender, mut receiver) = mpsc::channel(32);
-        let connections = Arc::new(Mutex::new(Vec::new())); // Initialize with a shared, mutable vector
-        let manager = WebSocketManager { sender, connections: connections.clone() };
+    /// Reads the next line from a connection, without its trailing `\n` or `\r\n`.
+    ///
+    /// If `timeout_ms` passes first, `ReqlyError::Timeout` is returned and the bytes read so
+    /// far are kept for the next read. A last line without a newline is returned as is; once
+    /// the peer closed the connection, the error is of kind `UnexpectedEof`.
+    pub async fn read_line(&self, connection_id: usize, timeout_ms: Option<u64>) -> Result<String, ReqlyError> {
+        let conn = self.connection(connection_id).await.ok_or(ReqlyError::ConnectionNotFound(connection_id))?;
+        let mut reader = conn.reader.lock().await;
+        let reader = &mut *reader;
 
-        tokio::spawn(async move {
-            while let Some(command) = receiver.recv().await {
-                match command {
-                    WebSocketCommand::SendMessage(id, msg) => {
-                        if let Some(conn) = connections.lock().await.get_mut(id) {
-                            let _ = conn.send(Message::Text(msg)).await;
-                            debug!("Sent message to connection {}", id);
-                        }
-                    }
-                    WebSocketCommand::Close(id) => {
-                        let mut conns = connections.lock().await;
-                        if id < conns.len() {
-                            conns.remove(id); // Properly remove the connection
-                            debug!("Closed connection {}", id);
-                        }
-                    }
-                }
-            }
-        });
+        let read = reader.stream.read_until(b'\n', &mut reader.partial_line);
+        match timeout_ms {
+            Some(timeout_ms) => tokio::time::timeout(Duration::from_millis(timeout_ms), read)
+                .await
+                .map_err(|_| ReqlyError::Timeout)??,
+            None => read.await?,
+        };
+        if reader.partial_line.is_empty() {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
 
-        info!("WebSocket Manager created");
-        manager
+        let mut line = std::mem::take(&mut reader.partial_line);
+        if line.last() == Some(&b'\n') {
+            line.pop();
+            if line.last() == Some(&b'\r') {
+                line.pop();
+            }
+        }
+        debug!("Read a line of {} bytes from connection {}", line.len(), connection_id);
+        String::from_utf8(line).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e).into())
     }
 
-    pub async fn connect(&self, url: &str) -> Result<usize, Box<dyn Error>> {
-        let url = Url::parse(url)?;
-        let (ws_stream, _) = connect_async(url).await?;
-        let mut conns = self.connections.lock().await;
+    /// Reads one message framed by a 4-byte big-endian length prefix and returns its payload.
+    ///
+    /// Payloads larger than 16 MiB are rejected with an error of kind `InvalidData`. The read
+    /// waits for the whole frame without a time limit, but only ever holds up reads on the same
+    /// connection; wrap it in `tokio::time::timeout` to bound it.
+    pub async fn read_framed(&self, connection_id: usize) -> Result<Vec<u8>, ReqlyError> {
+        let conn = self.connection(connection_id).await.ok_or(ReqlyError::ConnectionNotFound(connection_id))?;
+        let mut reader = conn.reader.lock().await;
 
-        let id = conns.len();  // Get new ID for the connection
-        conns.push(ws_stream); // Store the connection
+        let mut prefix = [0; 4];
+        reader.read_exact(&mut prefix).await?;
+        let len = u32::from_be_bytes(prefix) as usize;
+        if len > MAX_FRAME_LEN {
+            let message = format!("frame of {} bytes exceeds the limit of {} bytes", len, MAX_FRAME_LEN);
+            return Err(io::Error::new(io::ErrorKind::InvalidData, message).into());
+        }
+        let mut payload = vec![0; len];
+        reader.read_exact(&mut payload).await?;
+        debug!("Read a frame of {} bytes from connection {}", len, connection_id);
+        Ok(payload)
+    }
 
-        info!("Connected to WebSocket server, connection ID: {}", id);
-        Ok(id) // Return the new connection ID
+    /// Sends `payload` prefixed with its length as 4 bytes in big-endian order.
+    pub async fn write_framed(&self, connection_id: usize, payload: Vec<u8>) -> Result<(), ReqlyError> {
+        let len = u32::try_from(payload.len())
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "payload is too large to frame"))?;
+        let mut frame = Vec::with_capacity(4 + payload.len());
+        frame.extend_from_slice(&len.to_be_bytes());
+        frame.extend_from_slice(&payload);
+
+        let conn = self.connection(connection_id).await.ok_or(ReqlyError::ConnectionNotFound(connection_id))?;
+        conn.writer.lock().await.write_all(&frame).await?;
+        debug!("Wrote a frame of {} bytes to connection {}", payload.len(), connection_id);
+        Ok(())
     }
 
-    pub async fn send_message(&self, connection_id: usize, message: String) -> Result<(), Box<dyn Error>> {
-        let mut conns = self.connections.lock().await;
-        if let Some(conn) = conns.get_mut(connection_id) {
-            conn.send(Message::Text(message)).await?;
-            debug!("Sent message to connection {}", connection_id);
+    /// Returns up to `n` bytes waiting on a connection without removing them from the read buffer.
+    ///
+    /// Waits until at least one byte is available; fewer than `n` bytes may be returned.
+    pub async fn peek(&self, connection_id: usize, n: usize) -> Result<Vec<u8>, ReqlyError> {
+        let conn = self.connection(connection_id).await.ok_or(ReqlyError::ConnectionNotFound(connection_id))?;
+        let mut reader = conn.reader.lock().await;
+        let buffered: Vec<u8> = reader.partial_line.iter().chain(reader.stream.buffer()).take(n).copied().collect();
+        if !buffered.is_empty() {
+            return Ok(buffered);
         }
+        let mut buffer = vec![0; n];
+        let len = reader.stream.get_mut().peek(&mut buffer).await?;
+        buffer.truncate(len);
+        Ok(buffer)
+    }
+
+    /// Shuts down the write half of a connection, signalling EOF to the peer.
+    ///
+    /// The connection can still be read from, which suits protocols where the peer only
+    /// answers once it has seen the complete request.
+    pub async fn shutdown_write(&self, connection_id: usize) -> Result<(), ReqlyError> {
+        let conn = self.connection(connection_id).await.ok_or(ReqlyError::ConnectionNotFound(connection_id))?;
+        conn.writer.lock().await.shutdown().await?;
+        debug!("Shut down write half of connection {}", connection_id);
         Ok(())
     }
 
     pub async fn close_connection(&self, connection_id: usize) {
-        let _ = self.sender.send(WebSocketCommand::Close(connection_id)).await;
-        debug!("Requested to close connection {}", connection_id);
+        let _ = self.sender.send(TcpCommand::Close(connection_id)).await;
+    }
+
+    /// Shuts the manager down gracefully: stops the `listen` loops, waits for the queued
+    /// close commands to be handled, then shuts down the write half of every connection.
+    ///
+    /// Sends are not queued: `send_message` writes to the socket itself, so the messages to
+    /// flush are those whose send returned or is in progress. Each `shutdown_write` waits for
+    /// the send in progress on its connection, so the peer sees EOF only after the message.
+    /// The remaining connections can still be read from. Returns `ReqlyError::Timeout` if all
+    /// this takes more than 10 seconds, or the first error of a `shutdown_write`.
+    pub async fn shutdown(&self) -> Result<(), ReqlyError> {
+        self.shutdown.send_replace(true);
+
+        let shutdown = async {
+            let (done, drained) = oneshot::channel();
+            if self.sender.send(TcpCommand::Drain(done)).await.is_ok() {
+                let _ = drained.await;
+            }
+
+            let mut ids: Vec<usize> = self.connections.lock().await.keys().copied().collect();
+            ids.sort_unstable();
+            let mut result = Ok(());
+            for id in ids {
+                if let Err(e) = self.shutdown_write(id).await {
+                    result = result.and(Err(e));
+                }
+            }
+            result
+        };
+        let result = tokio::time::timeout(SHUTDOWN_TIMEOUT, shutdown).await.map_err(|_| ReqlyError::Timeout)?;
+        info!("TCP Manager shut down");
+        result
     }
 
-    pub async fn get_connections(&self) -> tokio::sync::MutexGuard<'_, Vec<WebSocketConnection>> {
-        self.connections.lock().await
+    async fn connection(&self, connection_id: usize) -> Option<Arc<TcpConnection>> {
+        self.connections.lock().await.get(&connection_id).cloned()
     }
 }
 
-#[tokio::test]
-async fn test_websocket_connection() {
-    let manager = WebSocketManager::new();
-    let connection_id = manager.connect("ws://localhost:8765").await.unwrap();
-
-    let message = "hello!".to_string();
-    manager.send_message(connection_id, message.clone()).await.unwrap();
+impl Default for TcpManager {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
-    sleep(Duration::from_millis(100)).await;
+impl UdpManager {
+    fn from_socket(socket: UdpSocket) -> Self {
+        UdpManager {
+            socket: Arc::new(socket),
+            dtls: StdMutex::new(None),
+            #[cfg(test)]
+            drop_rate: StdMutex::new(0.0),
+        }
+    }
 
-    let mut conns = manager.get_connections().await;
-    let conn = conns.get_mut(connection_id).unwrap();
-    let msg = conn.next().await.unwrap().unwrap();
+    pub async fn new(addr: &str) -> Result<Self, Box<dyn Error>> {
+        let socket = UdpSocket::bind(addr).await?;
+        info!("UDP Manager created");
+        Ok(UdpManager::from_socket(socket))
+    }
 
-    assert_eq!(msg, Message::Text(message));
+    /// Creates a manager bound to the IPv6 socket address `addr`, such as `[::1]:5006`.
+    ///
+    /// Fails with an error of kind `InvalidInput` if `addr` is not an IPv6 socket address.
+    pub async fn new_v6(addr: &str) -> Result<Self, ReqlyError> {
+        let addr = addr
+            .parse::<SocketAddr>()
+            .ok()
+            .filter(SocketAddr::is_ipv6)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!("not an IPv6 socket address: {}", addr)))?;
+        let socket = UdpSocket::bind(addr).await?;
+        info!("UDP Manager created on {}", addr);
+        Ok(UdpManager::from_socket(socket))
+    }
 
-    manager.close_connection(connection_id).await;
-    
-    //todo: debug this not being 0:
-    //assert_eq!(conns.len(), 0);
-}
+    /// Performs a DTLS handshake with the peer at `addr`.
+    ///
+    /// Afterwards the socket only receives datagrams from that peer: `send_message` encrypts
+    /// messages sent to it and `receive_message` decrypts the messages it sends.
+    pub async fn connect_dtls(&self, addr: &str, config: DtlsConfig) -> Result<(), ReqlyError> {
+        let peer = lookup_host(addr)
+            .await?
+            .next()
+            .ok_or_else(|| ReqlyError::InvalidUrl(addr.to_string()))?;
+        let ssl = config.ssl().map_err(|e| ReqlyError::Dtls(e.to_string()))?;
+        self.socket.connect(peer).await?;
 
-#[tokio::test]
-async fn test_socketio_connection() {
-    let socket = ClientBuilder::new("http://localhost:5001")
-        .namespace("/")
-        .on("message", |payload: Option<Payload>, socket, _| {
-            async move {
-                match payload {
-                    Some(Payload::Json(msg)) => {
-                        if let Some(msg) = msg.as_str() {
-                            println!("Received message: {}", msg);
-                            socket.emit("message", Payload::Json(json!(format!("Client received: {}", msg)))).await.unwrap();
-                        } else {
-                            println!("Received unexpected JSON payload");
-                        }
+        let handshake = async {
+            let mut result = ssl.connect(DtlsChannel { socket: self.socket.clone() });
+            loop {
+                match result {
+                    Ok(stream) => return Ok(stream),
+                    Err(HandshakeError::WouldBlock(handshake)) => {
+                        self.wait_for_dtls_socket(handshake.error().code()).await?;
+                        result = handshake.handshake();
                     }
-                    _ => println!("Received unexpected payload"),
+                    Err(e) => return Err(ReqlyError::Dtls(e.to_string())),
                 }
             }
-            .boxed()
-        })
-        .connect()
-        .await
-        .expect("Failed to connect");
-
-    socket.emit("message", Payload::Json(json!("hello!"))).await.unwrap();
-
-    sleep(Duration::from_millis(100)).await;
-
-    socket.disconnect().await.unwrap();
-}
+        };
+        let stream = tokio::time::timeout(DTLS_HANDSHAKE_TIMEOUT, handshake)
+            .await
+            .map_err(|_| ReqlyError::Timeout)??;
 
-#[tokio::test]
-async fn test_udp_connection() {
-    let manager = UdpManager::new("127.0.0.1:0").await.unwrap();
+        *self.dtls.lock().unwrap_or_else(|e| e.into_inner()) = Some(DtlsSession { peer, stream });
+        info!("DTLS session established with {}", peer);
+        Ok(())
+    }
 
-    let message = "hello!".to_string();
-    manager.send_message(message.as_bytes().to_vec(), "127.0.0.1:5006").await.unwrap();
+    /// Makes `send_message` drop outgoing packets with the given probability, to exercise
+    /// the retransmission logic of protocols in tests.
+    #[cfg(test)]
+    pub(crate) fn set_drop_rate(&self, probability: f64) {
+        *self.drop_rate.lock().unwrap_or_else(|e| e.into_inner()) = probability.clamp(0.0, 1.0);
+    }
 
-    sleep(Duration::from_millis(100)).await;
+    pub async fn send_message(&self, message: Vec<u8>, addr: &str) -> Result<(), Box<dyn Error>> {
+        #[cfg(test)]
+        {
+            let drop_rate = *self.drop_rate.lock().unwrap_or_else(|e| e.into_inner());
+            if rand::random::<f64>() < drop_rate {
+                debug!("Dropped UDP message to {}", addr);
+                return Ok(());
+            }
+        }
+        if let Some(peer) = self.dtls_peer() {
+            if lookup_host(addr).await?.any(|addr| addr == peer) {
+                self.dtls_io(|stream| stream.ssl_write(&message)).await?;
+                debug!("Sent DTLS message to {}", addr);
+                return Ok(());
+            }
+        }
+        self.socket.send_to(&message, addr).await?;
+        debug!("Sent UDP message to {}", addr);
+        Ok(())
+    }
+
+    pub async fn receive_message(&self) -> Result<(Vec<u8>, String), Box<dyn Error>> {
+        if let Some(peer) = self.dtls_peer() {
+            let mut buffer = vec![0; DTLS_MTU as usize];
+            let len = self.dtls_io(|stream| stream.ssl_read(&mut buffer)).await?;
+            buffer.truncate(len);
+            debug!("Received DTLS message from {}", peer);
+            return Ok((buffer, peer.to_string()));
+        }
+
+        let mut buffer = vec![0; 1024];
+        let (len, addr) = self.socket.recv_from(&mut buffer).await?;
+        buffer.truncate(len);
+        debug!("Received UDP message from {}", addr);
+        Ok((buffer, addr.to_string()))
+    }
+
+    /// Receives one packet that must be exactly `expected_len` bytes long, as in protocols with
+    /// fixed-size messages such as NTP.
+    ///
+    /// A packet of another length is consumed and reported as an error of kind `InvalidData`.
+    /// If `timeout_ms` passes before a packet arrives, `ReqlyError::Timeout` is returned.
+    pub async fn receive_exact(&self, expected_len: usize, timeout_ms: Option<u64>) -> Result<(Vec<u8>, String), ReqlyError> {
+        let receive = async {
+            let mut buffer = vec![0; MAX_DATAGRAM_LEN];
+            let (len, addr) = match self.dtls_peer() {
+                Some(peer) => (self.dtls_io(|stream| stream.ssl_read(&mut buffer)).await?, peer),
+                None => self.socket.recv_from(&mut buffer).await?,
+            };
+            buffer.truncate(len);
+            Ok::<_, ReqlyError>((buffer, addr))
+        };
+        let (buffer, addr) = match timeout_ms {
+            Some(timeout_ms) => tokio::time::timeout(Duration::from_millis(timeout_ms), receive)
+                .await
+                .map_err(|_| ReqlyError::Timeout)??,
+            None => receive.await?,
+        };
+
+        if buffer.len() != expected_len {
+            let message = format!("received a packet of {} bytes, expected {} bytes", buffer.len(), expected_len);
+            return Err(io::Error::new(io::ErrorKind::InvalidData, message).into());
+        }
+        debug!("Received {} bytes from {}", expected_len, addr);
+        Ok((buffer, addr.to_string()))
+    }
+
+    /// Receives the next packet sent by `expected_sender`, discarding the packets of any other
+    /// sender meanwhile.
+    ///
+    /// `expected_sender` is resolved like the address of `send_message`. If `timeout_ms`
+    /// passes before a packet from it arrives, `ReqlyError::Timeout` is returned. During a
+    /// DTLS session only the peer's packets are received, decrypted.
+    pub async fn receive_from(&self, expected_sender: &str, timeout_ms: Option<u64>) -> Result<Vec<u8>, ReqlyError> {
+        let expected: Vec<SocketAddr> = lookup_host(expected_sender).await?.collect();
+        let receive = async {
+            let mut buffer = vec![0; MAX_DATAGRAM_LEN];
+            loop {
+                let (len, addr) = match self.dtls_peer() {
+                    Some(peer) => (self.dtls_io(|stream| stream.ssl_read(&mut buffer)).await?, peer),
+                    None => self.socket.recv_from(&mut buffer).await?,
+                };
+                if expected.contains(&addr) {
+                    buffer.truncate(len);
+                    return Ok::<_, ReqlyError>(buffer);
+                }
+                debug!("Discarded {} bytes from unexpected sender {}", len, addr);
+            }
+        };
+        let buffer = match timeout_ms {
+            Some(timeout_ms) => tokio::time::timeout(Duration::from_millis(timeout_ms), receive)
+                .await
+                .map_err(|_| ReqlyError::Timeout)??,
+            None => receive.await?,
+        };
+        debug!("Received {} bytes from {}", buffer.len(), expected_sender);
+        Ok(buffer)
+    }
+
+    /// Sets the IP time-to-live of outgoing packets, which bounds the hops of multicast traffic.
+    pub fn set_ttl(&self, ttl: u32) -> Result<(), ReqlyError> {
+        self.socket.set_ttl(ttl)?;
+        Ok(())
+    }
+
+    /// Returns the IP time-to-live of outgoing packets.
+    pub fn ttl(&self) -> Result<u32, ReqlyError> {
+        Ok(self.socket.ttl()?)
+    }
+
+    fn dtls_peer(&self) -> Option<SocketAddr> {
+        self.dtls.lock().unwrap_or_else(|e| e.into_inner()).as_ref().map(|session| session.peer)
+    }
+
+    /// Runs `op` on the DTLS stream until it no longer waits for the socket.
+    async fn dtls_io<T>(
+        &self,
+        mut op: impl FnMut(&mut SslStream<DtlsChannel>) -> Result<T, openssl::ssl::Error>,
+    ) -> Result<T, ReqlyError> {
+        loop {
+            let code = {
+                let mut session = self.dtls.lock().unwrap_or_else(|e| e.into_inner());
+                let session = session.as_mut().ok_or_else(|| ReqlyError::Dtls("no DTLS session".to_string()))?;
+                match op(&mut session.stream) {
+                    Ok(value) => return Ok(value),
+                    Err(e) if matches!(e.code(), ErrorCode::WANT_READ | ErrorCode::WANT_WRITE) => e.code(),
+                    Err(e) => return Err(ReqlyError::Dtls(e.to_string())),
+                }
+            };
+            self.wait_for_dtls_socket(code).await?;
+        }
+    }
+
+    /// Waits until the socket can do what a DTLS operation failing with `code` wants.
+    async fn wait_for_dtls_socket(&self, code: ErrorCode) -> io::Result<()> {
+        if code == ErrorCode::WANT_WRITE {
+            self.socket.writable().await
+        } else {
+            self.socket.readable().await
+        }
+    }
+}
+
+impl WebSocketManager {
+    pub fn new() -> Self {
+        let (sender, mut receiver) = mpsc::channel(COMMAND_QUEUE_CAPACITY);
+        let shared = Arc::new(WsShared {
+            connections: Mutex::new(HashMap::new()),
+            ended: Mutex::new(HashMap::new()),
+            states: Mutex::new(HashMap::new()),
+            next_id: AtomicUsize::new(0),
+            rate_limits: Mutex::new(HashMap::new()),
+            idle_timeouts: Mutex::new(HashMap::new()),
+            error_handler: StdMutex::new(None),
+            labels: StdMutex::new(HashMap::new()),
+            reconnect_handlers: StdMutex::new(HashMap::new()),
+            subscriptions: StdMutex::new(HashMap::new()),
+            ordered: StdMutex::new(HashMap::new()),
+            deduplication: StdMutex::new(HashMap::new()),
+        });
+        let manager = WebSocketManager { sender, shared: shared.clone() };
+
+        tokio::spawn(async move {
+            while let Some(command) = receiver.recv().await {
+                match command {
+                    WebSocketCommand::Close(id, frame, done) => {
+                        // Taken out of the map first, so the connection no longer counts as open
+                        // and its reader does not reconnect it; the rest is removed once closed.
+                        let conn = shared.connections.lock().await.remove(&id);
+                        // Each handshake runs in its own task, so an unresponsive peer delays
+                        // neither the other closes nor the commands queued behind it.
+                        let shared = shared.clone();
+                        tokio::spawn(async move {
+                            if let Some(conn) = conn {
+                                // Nobody receives on the connection anymore, so its reader must
+                                // not wait for queue space before it sees the peer's close frame.
+                                conn.inbound.set_cap(usize::MAX);
+                                let mut ended = conn.ended.subscribe();
+                                let mut sink = conn.sink.lock().await;
+                                let error = sink.send(Message::Close(frame)).await.err();
+                                let _ = sink.close().await;
+                                drop(sink);
+                                if error.is_none() {
+                                    // The peer's close frame ends the stream read by the reader
+                                    // task.
+                                    let handshake = ended.wait_for(|ended| *ended);
+                                    let _ = tokio::time::timeout(CLOSE_HANDSHAKE_TIMEOUT, handshake).await;
+                                }
+                                if let Some(reader) = conn.reader.lock().unwrap_or_else(|e| e.into_inner()).take() {
+                                    reader.abort();
+                                }
+                                if let Some(e) = error {
+                                    shared.report_error(id, &e.into());
+                                }
+                                debug!("{}Closed connection {}", shared.log_prefix(id), id);
+                                shared.remove_connection(id).await;
+                            }
+                            let _ = done.send(());
+                        });
+                    }
+                }
+            }
+        });
+
+        info!("WebSocket Manager created");
+        manager
+    }
+
+    pub async fn connect(&self, url: &str) -> Result<usize, Box<dyn Error>> {
+        let url = Url::parse(url)?;
+        Ok(self.shared.open(url.into_client_request()?).await?)
+    }
+
+    /// Connects like `connect`, adding `headers` to the handshake request (e.g. cookies or auth).
+    pub async fn connect_with_headers(&self, url: &str, headers: &[(&str, &str)]) -> Result<usize, ReqlyError> {
+        let mut request = url.into_client_request()?;
+        for (name, value) in headers {
+            let header_name = HeaderName::from_bytes(name.as_bytes())
+                .map_err(|_| ReqlyError::InvalidHeader(name.to_string()))?;
+            let header_value = HeaderValue::from_str(value)
+                .map_err(|_| ReqlyError::InvalidHeader(format!("{}: {}", name, value)))?;
+            request.headers_mut().append(header_name, header_value);
+        }
+        self.shared.open(request).await
+    }
+
+    /// Sends a text message, or fails with `ReqlyError::ConnectionNotFound` if there is no
+    /// such connection, e.g. because the peer closed it.
+    pub async fn send_message(&self, connection_id: usize, message: String) -> Result<(), Box<dyn Error>> {
+        Ok(self.send_text(connection_id, message).await?)
+    }
+
+    /// Serializes `value` to JSON and sends it as a text message.
+    pub async fn send_json<T: Serialize>(&self, connection_id: usize, value: &T) -> Result<(), ReqlyError> {
+        self.send_text(connection_id, serde_json::to_string(value)?).await
+    }
+
+    /// Sends a text message for `send_message` and `send_json`, once the send rate limit
+    /// allows it and in its sequence envelope if the connection has ordered delivery.
+    async fn send_text(&self, connection_id: usize, text: String) -> Result<(), ReqlyError> {
+        self.wait_for_send_slots(connection_id, 1).await;
+
+        let conn = self
+            .connection(connection_id)
+            .await
+            .ok_or(ReqlyError::ConnectionNotFound(connection_id))?;
+        let mut sink = conn.sink.lock().await;
+        let sent = sink.send(Message::Text(self.shared.sequenced(connection_id, text))).await;
+        drop(sink);
+        sent.map_err(|e| self.shared.reported(connection_id, e))?;
+        self.shared.record_activity(connection_id).await;
+        debug!("{}Sent message to connection {}", self.shared.log_prefix(connection_id), connection_id);
+        Ok(())
+    }
+
+    /// Waits for the next message on a connection and deserializes it from JSON.
+    ///
+    /// Text and binary messages are both accepted. If the peer closes the connection first,
+    /// the error wraps tungstenite's `ConnectionClosed`.
+    pub async fn receive_json<T: DeserializeOwned>(&self, connection_id: usize, timeout_ms: Option<u64>) -> Result<T, ReqlyError> {
+        let value = match self.receive_message(connection_id, timeout_ms).await? {
+            Some(WsMessage::Text(text)) => serde_json::from_str(&text)?,
+            Some(WsMessage::Binary(data)) => serde_json::from_slice(&data)?,
+            None => return Err(tokio_tungstenite::tungstenite::Error::ConnectionClosed.into()),
+        };
+        Ok(value)
+    }
+
+    /// Sends several text messages on a connection, taking its sink lock only once.
+    ///
+    /// The messages are buffered in order and written to the socket together, so they go out
+    /// as one burst. A limit set with `set_send_rate_limit` counts every message of the burst,
+    /// which waits until the limit allows the last of them.
+    pub async fn send_many(&self, connection_id: usize, messages: Vec<String>) -> Result<(), ReqlyError> {
+        let count = messages.len();
+        self.wait_for_send_slots(connection_id, count).await;
+
+        let conn = self
+            .connection(connection_id)
+            .await
+            .ok_or(ReqlyError::ConnectionNotFound(connection_id))?;
+
+        let mut sink = conn.sink.lock().await;
+        conn.batching.store(true, Ordering::SeqCst);
+        let mut queued = Ok(());
+        for message in messages {
+            queued = sink.feed(Message::Text(self.shared.sequenced(connection_id, message))).await;
+            if queued.is_err() {
+                break;
+            }
+        }
+        // Whatever was queued is written out even if a later message failed.
+        conn.batching.store(false, Ordering::SeqCst);
+        let flushed = sink.flush().await;
+        drop(sink);
+        queued.and(flushed).map_err(|e| self.shared.reported(connection_id, e))?;
+
+        self.shared.record_activity(connection_id).await;
+        debug!("{}Sent {} messages to connection {}", self.shared.log_prefix(connection_id), count, connection_id);
+        Ok(())
+    }
+
+    /// Sends a ping carrying the current time and returns the round-trip time until the
+    /// matching pong arrives.
+    ///
+    /// If no pong arrives within 5 seconds, `ReqlyError::Timeout` is returned.
+    pub async fn measure_latency(&self, connection_id: usize) -> Result<Duration, ReqlyError> {
+        let conn = self
+            .connection(connection_id)
+            .await
+            .ok_or(ReqlyError::ConnectionNotFound(connection_id))?;
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        let payload = timestamp.as_nanos().to_be_bytes().to_vec();
+        let (waiter, pong) = oneshot::channel();
+        *conn.pong.lock().unwrap_or_else(|e| e.into_inner()) = Some((payload.clone(), waiter));
+
+        let started = Instant::now();
+        let ping = async {
+            let sent = conn.sink.lock().await.send(Message::Ping(payload)).await;
+            sent.map_err(|e| self.shared.reported(connection_id, e))?;
+            conn.wait_for_reply(pong).await
+        };
+        let result = tokio::time::timeout(PONG_TIMEOUT, ping).await;
+        conn.pong.lock().unwrap_or_else(|e| e.into_inner()).take();
+
+        let received = result.map_err(|_| ReqlyError::Timeout)??;
+        self.shared.record_activity(connection_id).await;
+        let latency = received.duration_since(started);
+        debug!(
+            "{}Measured a latency of {:?} on connection {}",
+            self.shared.log_prefix(connection_id),
+            latency,
+            connection_id
+        );
+        Ok(latency)
+    }
+
+    /// Sends a JSON-RPC 2.0 request and waits for the response with the same `id`.
+    ///
+    /// Returns the `result` of the response, or `ReqlyError::JsonRpc` for an `error` object.
+    /// Calls may overlap: each response goes to its own call, and other messages arriving
+    /// meanwhile are kept for `receive_message`. If no response arrives within `timeout_ms`,
+    /// `ReqlyError::Timeout` is returned.
+    ///
+    /// The request is sent without a sequence envelope, even with `enable_ordered_delivery`,
+    /// since the response is matched by its `id` rather than by its order.
+    pub async fn json_rpc_call(
+        &self,
+        connection_id: usize,
+        method: &str,
+        params: serde_json::Value,
+        timeout_ms: u64,
+    ) -> Result<serde_json::Value, ReqlyError> {
+        let conn = self
+            .connection(connection_id)
+            .await
+            .ok_or(ReqlyError::ConnectionNotFound(connection_id))?;
+        let id = conn.next_rpc_id.fetch_add(1, Ordering::SeqCst);
+        let (waiter, response) = oneshot::channel();
+        conn.rpc_waiters.lock().unwrap_or_else(|e| e.into_inner()).insert(id, waiter);
+
+        let request = serde_json::json!({ "jsonrpc": "2.0", "id": id, "method": method, "params": params });
+        let call = async {
+            self.wait_for_send_slots(connection_id, 1).await;
+            let sent = conn.sink.lock().await.send(Message::Text(request.to_string())).await;
+            sent.map_err(|e| self.shared.reported(connection_id, e))?;
+            self.shared.record_activity(connection_id).await;
+            conn.wait_for_reply(response).await
+        };
+        let result = tokio::time::timeout(Duration::from_millis(timeout_ms), call).await;
+        conn.rpc_waiters.lock().unwrap_or_else(|e| e.into_inner()).remove(&id);
+
+        let mut response = result.map_err(|_| ReqlyError::Timeout)??;
+        debug!(
+            "{}Received JSON-RPC response {} on connection {}",
+            self.shared.log_prefix(connection_id),
+            id,
+            connection_id
+        );
+        if let Some(error) = response.get_mut("error") {
+            return Err(ReqlyError::JsonRpc {
+                code: error.get("code").and_then(serde_json::Value::as_i64).unwrap_or_default(),
+                message: error.get("message").and_then(serde_json::Value::as_str).unwrap_or_default().to_string(),
+                data: error.get_mut("data").map(serde_json::Value::take),
+            });
+        }
+        Ok(response.get_mut("result").map(serde_json::Value::take).unwrap_or_default())
+    }
+
+    /// Closes a connection with `CloseCode::Normal` once no message was sent or received on it
+    /// for `timeout_ms`. Calling this again replaces the timeout and restarts the timer.
+    pub async fn set_idle_timeout(&self, connection_id: usize, timeout_ms: u64) {
+        let timeout = Duration::from_millis(timeout_ms);
+        let previous = self.shared.idle_timeouts.lock().await.insert(connection_id, IdleTimeout {
+            timeout,
+            deadline: Instant::now() + timeout,
+        });
+        if previous.is_some() {
+            return; // The connection is already being watched.
+        }
+
+        let shared = self.shared.clone();
+        let sender = self.sender.clone();
+        tokio::spawn(async move {
+            loop {
+                let Some(deadline) = shared.idle_timeouts.lock().await.get(&connection_id).map(|idle| idle.deadline) else {
+                    return; // The timeout was removed because the connection closed.
+                };
+                sleep_until(deadline).await;
+
+                let mut idle_timeouts = shared.idle_timeouts.lock().await;
+                if idle_timeouts.get(&connection_id).is_some_and(|idle| idle.deadline <= Instant::now()) {
+                    idle_timeouts.remove(&connection_id);
+                    drop(idle_timeouts);
+                    if let Some(state) = shared.states.lock().await.get_mut(&connection_id) {
+                        *state = WsConnectionState::Closing;
+                    }
+                    let frame = CloseFrame { code: CloseCode::Normal, reason: "idle timeout".into() };
+                    let (done, _) = oneshot::channel();
+                    let _ = sender.send(WebSocketCommand::Close(connection_id, Some(frame), done)).await;
+                    info!("{}Closing idle connection {}", shared.log_prefix(connection_id), connection_id);
+                    return;
+                }
+            }
+        });
+    }
+
+    /// Limits the inbound messages of a connection that are read ahead of `receive_message`
+    /// to `max_messages`; by default, the reader task queues every message it reads.
+    ///
+    /// While the queue is full, the connection is not read, so a fast peer is slowed down by
+    /// TCP flow control instead of filling memory. This also delays noticing that the peer
+    /// closed the connection until there is room again. Setting the cap again changes it.
+    pub async fn set_receive_buffer_cap(&self, connection_id: usize, max_messages: usize) {
+        if let Some(conn) = self.connection(connection_id).await {
+            conn.inbound.set_cap(max_messages.max(1));
+        }
+    }
+
+    /// Names a connection in the log messages about it, which then start with `[ws:label]`.
+    ///
+    /// Setting a label again replaces it; the label is dropped when the connection closes.
+    pub fn set_label(&self, connection_id: usize, label: &str) {
+        self.shared.labels.lock().unwrap_or_else(|e| e.into_inner()).insert(connection_id, label.to_string());
+    }
+
+    /// Reconnects to the same URL, with the same handshake headers, whenever the peer closes
+    /// the connection, then calls `cb` with the ID of the new connection.
+    ///
+    /// The closure is noticed by the task reading the connection, which reconnects before the
+    /// old ID is removed. `cb` and the label of the connection carry over to the new
+    /// connection, so it is reconnected again the next time. A failed reconnection is
+    /// reported to the error handler, and closing a connection with `close_connection` never
+    /// reconnects it. Registering a callback again replaces it.
+    pub fn on_reconnect(&self, connection_id: usize, cb: impl Fn(usize) + Send + 'static) {
+        self.shared
+            .reconnect_handlers
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(connection_id, Box::new(cb));
+    }
+
+    /// Routes the text messages of `topic` on a connection to `cb` instead of `receive_message`.
+    ///
+    /// The topic of a message is the first word of the `type` field of its JSON object, so
+    /// `{"type":"chat","body":"hi"}` goes to the `chat` subscriber. Messages are routed by the
+    /// task reading the connection as they arrive, whether or not anyone calls
+    /// `receive_message`, which returns those matching no subscription as usual. Subscribing
+    /// to a topic again replaces its callback, and a callback may subscribe itself.
+    pub fn subscribe(&self, connection_id: usize, topic: &str, cb: impl Fn(String) + Send + Sync + 'static) {
+        let mut subscriptions = self.shared.subscriptions.lock().unwrap_or_else(|e| e.into_inner());
+        let subscribers = subscriptions.entry(connection_id).or_default();
+        subscribers.retain(|(subscribed, _)| subscribed != topic);
+        subscribers.push((topic.to_string(), Arc::new(cb)));
+    }
+
+    /// Numbers the text messages sent on a connection and delivers the received ones in the
+    /// order of their numbers.
+    ///
+    /// Each message goes out as a `{"seq": N, "payload": "..."}` envelope, with `N` counting
+    /// from 0, and the peer is expected to number its messages the same way. The payloads
+    /// arriving ahead of their turn are held back until the missing ones arrived. Once 64 are
+    /// held back, the missing ones are skipped and dropped if they arrive later. Received
+    /// messages without an envelope, and binary messages, are returned as they arrive.
+    ///
+    /// Any received JSON object with a numeric `seq` and a `payload` key counts as an
+    /// envelope, so the peer's own messages must not use these keys at the top level.
+    pub fn enable_ordered_delivery(&self, connection_id: usize) {
+        self.shared.ordered.lock().unwrap_or_else(|e| e.into_inner()).entry(connection_id).or_default();
+    }
+
+    /// Drops the messages received on a connection that repeat one of the last `window_size`
+    /// messages, before they are routed or returned by `receive_message`.
+    ///
+    /// Messages are compared by a 64-bit hash of their type and payload. Enabling it again
+    /// changes the window and forgets the messages seen so far; a `window_size` of 0 turns it off.
+    pub fn enable_deduplication(&self, connection_id: usize, window_size: usize) {
+        let mut deduplication = self.shared.deduplication.lock().unwrap_or_else(|e| e.into_inner());
+        if window_size == 0 {
+            deduplication.remove(&connection_id);
+        } else {
+            deduplication.insert(connection_id, Deduplication { window_size, recent: VecDeque::with_capacity(window_size) });
+        }
+    }
+
+    /// Calls `handler` with the connection ID and the error whenever reading or writing a
+    /// connection fails, e.g. when the peer resets it or fails the close handshake.
+    ///
+    /// A failed send is also returned by `send_message` and the like, and a failed read by
+    /// `receive_message`. Setting a handler replaces the previous one.
+    pub fn set_error_handler(&self, handler: impl Fn(usize, &ReqlyError) + Send + Sync + 'static) {
+        *self.shared.error_handler.lock().unwrap_or_else(|e| e.into_inner()) = Some(Arc::new(handler));
+    }
+
+    /// Returns the number of connections currently held by the manager.
+    pub async fn connection_count(&self) -> usize {
+        self.shared.connections.lock().await.len()
+    }
+
+    /// Closes a connection, returning once the close handshake with the peer is done.
+    pub async fn close_connection(&self, connection_id: usize) {
+        if let Some(state) = self.shared.states.lock().await.get_mut(&connection_id) {
+            *state = WsConnectionState::Closing;
+        }
+        let prefix = self.shared.log_prefix(connection_id);
+        let (done, closed) = oneshot::channel();
+        if self.sender.send(WebSocketCommand::Close(connection_id, None, done)).await.is_ok() {
+            let _ = closed.await;
+        }
+        debug!("{}Closed connection {}", prefix, connection_id);
+    }
+
+    /// Closes every open connection with a close frame, returning once all close handshakes
+    /// are done.
+    ///
+    /// The connections are closed concurrently, each waiting up to 5 seconds for its peer. If
+    /// they are not all closed within 10 seconds, `ReqlyError::Timeout` is returned and the
+    /// remaining ones keep closing in the background.
+    pub async fn shutdown_all(&self) -> Result<(), ReqlyError> {
+        let ids = self.with_connections(|ids| ids.to_vec()).await;
+        let closes = futures::future::join_all(ids.iter().map(|&id| self.close_connection(id)));
+        tokio::time::timeout(SHUTDOWN_TIMEOUT, closes).await.map_err(|_| ReqlyError::Timeout)?;
+        info!("Shut down {} connections", ids.len());
+        Ok(())
+    }
+
+    /// Requests to close a connection like `close_connection`, without waiting for queue space
+    /// or for the close to complete.
+    ///
+    /// Returns `ReqlyError::QueueFull` if the command queue is full.
+    pub async fn try_close_connection(&self, connection_id: usize) -> Result<(), ReqlyError> {
+        let (done, _) = oneshot::channel();
+        if let Err(mpsc::error::TrySendError::Full(_)) = self.sender.try_send(WebSocketCommand::Close(connection_id, None, done)) {
+            return Err(ReqlyError::QueueFull);
+        }
+        if let Some(state) = self.shared.states.lock().await.get_mut(&connection_id) {
+            *state = WsConnectionState::Closing;
+        }
+        debug!("{}Requested to close connection {}", self.shared.log_prefix(connection_id), connection_id);
+        Ok(())
+    }
+
+    /// Returns the number of commands waiting to be processed by the background task.
+    pub fn pending_commands(&self) -> usize {
+        self.sender.max_capacity() - self.sender.capacity()
+    }
+
+    /// Limits a connection to `messages_per_second` outgoing messages, without bursts.
+    ///
+    /// A rate that is not positive removes the limit.
+    pub async fn set_send_rate_limit(&self, connection_id: usize, messages_per_second: f64) {
+        let mut rate_limits = self.shared.rate_limits.lock().await;
+        if messages_per_second > 0.0 {
+            rate_limits.insert(connection_id, SendRateLimit {
+                interval: Duration::from_secs_f64(1.0 / messages_per_second),
+                next_send: Instant::now(),
+            });
+        } else {
+            rate_limits.remove(&connection_id);
+        }
+    }
+
+    /// Sleeps until the rate limit of the connection, if it has one, allows the last of
+    /// `count` more messages, taking a slot for each.
+    async fn wait_for_send_slots(&self, connection_id: usize, count: usize) {
+        if count == 0 {
+            return;
+        }
+        let send_at = {
+            let mut rate_limits = self.shared.rate_limits.lock().await;
+            let Some(limit) = rate_limits.get_mut(&connection_id) else {
+                return;
+            };
+            let first = limit.next_send.max(Instant::now());
+            limit.next_send = first + limit.interval * count as u32;
+            first + limit.interval * (count - 1) as u32
+        };
+        sleep_until(send_at).await;
+    }
+
+    /// Returns the current state of a connection; connections that were removed are `Closed`.
+    pub async fn state(&self, connection_id: usize) -> WsConnectionState {
+        self.shared
+            .states
+            .lock()
+            .await
+            .get(&connection_id)
+            .copied()
+            .unwrap_or(WsConnectionState::Closed)
+    }
+
+    /// Waits for the next text or binary message on a connection.
+    ///
+    /// Returns `Ok(None)` once the peer closed the connection, and `ReqlyError::Timeout` if
+    /// no message arrived within `timeout_ms`.
+    ///
+    /// The connection is read in the background: one closed by the peer is answered with a
+    /// close frame and removed as soon as the close arrives, so its state becomes `Closed` and
+    /// it no longer counts towards `connection_count`. With an `on_reconnect` callback, it is
+    /// replaced by a new connection first. Messages that arrived before the close can still be
+    /// received, and are followed by `Ok(None)`; once they are, or right away if there were
+    /// none, the ID is unknown and `ReqlyError::ConnectionNotFound` is returned.
+    pub async fn receive_message(&self, connection_id: usize, timeout_ms: Option<u64>) -> Result<Option<WsMessage>, ReqlyError> {
+        let conn = match self.connection(connection_id).await {
+            Some(conn) => conn,
+            None => self
+                .shared
+                .ended
+                .lock()
+                .await
+                .get(&connection_id)
+                .cloned()
+                .ok_or(ReqlyError::ConnectionNotFound(connection_id))?,
+        };
+
+        let message = match timeout_ms {
+            Some(timeout_ms) => tokio::time::timeout(Duration::from_millis(timeout_ms), conn.inbound.pop())
+                .await
+                .map_err(|_| ReqlyError::Timeout)?,
+            None => conn.inbound.pop().await,
+        }?;
+        if message.is_none() {
+            self.shared.ended.lock().await.remove(&connection_id);
+            return Ok(None);
+        }
+        debug!("{}Received message on connection {}", self.shared.log_prefix(connection_id), connection_id);
+        Ok(message)
+    }
+
+    /// Runs `f` on the IDs of the open connections, in ascending order.
+    ///
+    /// The lock is released as soon as `f` returns, so no guard can be held across an await.
+    pub async fn with_connections<F, R>(&self, f: F) -> R
+    where
+        F: FnOnce(&[usize]) -> R,
+    {
+        let mut ids: Vec<usize> = self.shared.connections.lock().await.keys().copied().collect();
+        ids.sort_unstable();
+        f(&ids)
+    }
+
+    /// Returns a connection, releasing the map lock before the caller uses it.
+    async fn connection(&self, connection_id: usize) -> Option<Arc<WsConnection>> {
+        self.shared.connections.lock().await.get(&connection_id).cloned()
+    }
+}
+
+impl WsShared {
+    /// Performs the handshake for `request`, stores the resulting connection and starts the
+    /// task reading it.
+    async fn open(self: &Arc<Self>, request: Request) -> Result<usize, ReqlyError> {
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst); // Get new ID for the connection
+        self.states.lock().await.insert(id, WsConnectionState::Connecting);
+
+        let uri = request.uri().clone();
+        let headers = request.headers().clone();
+        let batching = Arc::new(AtomicBool::new(false));
+        let ws_stream = match connect_batched(request, batching.clone()).await {
+            Ok(ws_stream) => ws_stream,
+            Err(e) => {
+                self.states.lock().await.remove(&id);
+                return Err(e.into());
+            }
+        };
+        let (sink, stream) = ws_stream.split();
+        let conn = Arc::new(WsConnection::new(uri, headers, batching, sink));
+        self.connections.lock().await.insert(id, conn.clone()); // Store the connection
+        self.states.lock().await.insert(id, WsConnectionState::Open);
+        self.start_reader(id, &conn, stream);
+
+        info!("Connected to WebSocket server, connection ID: {}", id);
+        Ok(id) // Return the new connection ID
+    }
+
+    /// Spawns the task reading a connection, see `read_connection`.
+    fn start_reader(self: &Arc<Self>, connection_id: usize, conn: &Arc<WsConnection>, stream: SplitStream<WebSocketConnection>) {
+        let reader = tokio::spawn(read_connection(Arc::downgrade(self), connection_id, conn.clone(), stream));
+        *conn.reader.lock().unwrap_or_else(|e| e.into_inner()) = Some(reader.abort_handle());
+    }
+
+    /// Handles a message read from a connection, returning the messages to queue for
+    /// `receive_message`: none if it is a duplicate, held back or routed, and possibly
+    /// several when it fills the gap before held back ones.
+    async fn received(&self, connection_id: usize, message: WsMessage) -> Vec<WsMessage> {
+        self.record_activity(connection_id).await;
+        if self.is_duplicate(connection_id, &message) {
+            return Vec::new();
+        }
+        let text = match message {
+            WsMessage::Text(text) => text,
+            binary => return vec![binary],
+        };
+        let Some(text) = self.unsequenced(connection_id, text) else {
+            return Vec::new();
+        };
+        std::iter::once(text)
+            .chain(std::iter::from_fn(|| self.next_in_sequence(connection_id)))
+            .filter_map(|text| self.route(connection_id, text))
+            .map(WsMessage::Text)
+            .collect()
+    }
+
+    /// Handles the end of the stream of a connection, after `error` if a read failed.
+    ///
+    /// A read error is reported to the error handler. A connection still in the map was
+    /// closed by the peer: the close handshake is completed, the connection reconnected if it
+    /// has an `on_reconnect` callback, then removed before the callback learns the new ID. A
+    /// connection being closed by `close_connection` only needs its reader to stop.
+    async fn connection_ended(self: &Arc<Self>, connection_id: usize, conn: &Arc<WsConnection>, error: Option<ReqlyError>) {
+        // Completes the close handshake started by the peer.
+        let _ = conn.sink.lock().await.close().await;
+        if let Some(e) = &error {
+            self.report_error(connection_id, e);
+        }
+        let closed_by_peer = self.connections.lock().await.contains_key(&connection_id);
+        if closed_by_peer {
+            info!("{}Connection {} was closed by the peer", self.log_prefix(connection_id), connection_id);
+            conn.inbound.finish(error);
+            if conn.inbound.has_unread() {
+                self.ended.lock().await.insert(connection_id, conn.clone());
+            }
+            let handler = self.reconnect_handlers.lock().unwrap_or_else(|e| e.into_inner()).remove(&connection_id);
+            let new_id = match handler {
+                Some(_) => self.reconnect(connection_id, conn).await,
+                None => None,
+            };
+            self.remove_connection(connection_id).await;
+            if let (Some(handler), Some(new_id)) = (handler, new_id) {
+                handler(new_id);
+                self.reconnect_handlers.lock().unwrap_or_else(|e| e.into_inner()).insert(new_id, handler);
+            }
+        }
+        conn.ended.send_replace(true);
+    }
+
+    /// Forgets a connection and everything configured for it, whether it was closed by
+    /// `close_connection` or by the peer.
+    async fn remove_connection(&self, connection_id: usize) {
+        self.connections.lock().await.remove(&connection_id);
+        self.states.lock().await.remove(&connection_id);
+        self.rate_limits.lock().await.remove(&connection_id);
+        self.idle_timeouts.lock().await.remove(&connection_id);
+        self.labels.lock().unwrap_or_else(|e| e.into_inner()).remove(&connection_id);
+        self.reconnect_handlers.lock().unwrap_or_else(|e| e.into_inner()).remove(&connection_id);
+        self.subscriptions.lock().unwrap_or_else(|e| e.into_inner()).remove(&connection_id);
+        self.ordered.lock().unwrap_or_else(|e| e.into_inner()).remove(&connection_id);
+        self.deduplication.lock().unwrap_or_else(|e| e.into_inner()).remove(&connection_id);
+    }
+
+    /// Passes an error of a connection to the error handler, if one is set.
+    ///
+    /// The handler is called with its lock released, so it may replace itself.
+    fn report_error(&self, connection_id: usize, error: &ReqlyError) {
+        let handler = self.error_handler.lock().unwrap_or_else(|e| e.into_inner()).clone();
+        if let Some(handler) = handler {
+            handler(connection_id, error);
+        }
+    }
+
+    /// Reports a failed write to a connection, returning the error for its caller.
+    fn reported(&self, connection_id: usize, error: impl Into<ReqlyError>) -> ReqlyError {
+        let error = error.into();
+        self.report_error(connection_id, &error);
+        error
+    }
+
+    /// Returns `true` if the connection has deduplication enabled and already saw `message`.
+    fn is_duplicate(&self, connection_id: usize, message: &WsMessage) -> bool {
+        let mut deduplication = self.deduplication.lock().unwrap_or_else(|e| e.into_inner());
+        deduplication
+            .get_mut(&connection_id)
+            .is_some_and(|deduplication| deduplication.is_duplicate(message))
+    }
+
+    /// Wraps an outgoing text message in its sequence envelope if the connection has ordered
+    /// delivery; the caller holds the sink lock, so the numbers follow the order on the wire.
+    fn sequenced(&self, connection_id: usize, message: String) -> String {
+        let mut ordered = self.ordered.lock().unwrap_or_else(|e| e.into_inner());
+        let Some(delivery) = ordered.get_mut(&connection_id) else {
+            return message;
+        };
+        let seq = delivery.next_send;
+        delivery.next_send += 1;
+        serde_json::json!({ "seq": seq, "payload": message }).to_string()
+    }
+
+    /// Unwraps an incoming text message of a connection with ordered delivery.
+    ///
+    /// Returns the payload if it is the next in sequence, or `None` if it is held back for
+    /// later or is a duplicate of a payload already delivered. Messages without an envelope
+    /// are returned as is. When too many are held back, the gap before the first held one is
+    /// skipped and that one is returned.
+    fn unsequenced(&self, connection_id: usize, text: String) -> Option<String> {
+        let mut ordered = self.ordered.lock().unwrap_or_else(|e| e.into_inner());
+        let Some(delivery) = ordered.get_mut(&connection_id) else {
+            return Some(text);
+        };
+        let Ok(message) = serde_json::from_str::<SequencedMessage>(&text) else {
+            return Some(text);
+        };
+        let payload = match message.payload {
+            serde_json::Value::String(payload) => payload,
+            payload => payload.to_string(),
+        };
+        if message.seq == delivery.next_receive {
+            delivery.next_receive += 1;
+            return Some(payload);
+        }
+        if message.seq > delivery.next_receive {
+            delivery.held.insert(message.seq, payload);
+        }
+        if delivery.held.len() > MAX_HELD_MESSAGES {
+            let (seq, payload) = delivery.held.pop_first()?;
+            debug!("{}Skipped messages {} to {}", self.log_prefix(connection_id), delivery.next_receive, seq - 1);
+            delivery.next_receive = seq + 1;
+            return Some(payload);
+        }
+        None
+    }
+
+    /// Returns the held payload that is next in sequence, if it already arrived.
+    fn next_in_sequence(&self, connection_id: usize) -> Option<String> {
+        let mut ordered = self.ordered.lock().unwrap_or_else(|e| e.into_inner());
+        let delivery = ordered.get_mut(&connection_id)?;
+        let payload = delivery.held.remove(&delivery.next_receive)?;
+        delivery.next_receive += 1;
+        Some(payload)
+    }
 
-    let (received, _) = manager.receive_message().await.unwrap();
-    assert_eq!(received, format!("Server received: {}", message).as_bytes().to_vec());
+    /// Hands `text` to the subscriber of its topic, or returns it if no subscriber matches.
+    ///
+    /// The subscriber is called with the subscriptions unlocked, so it may change them.
+    fn route(&self, connection_id: usize, text: String) -> Option<String> {
+        let subscriber = {
+            let subscriptions = self.subscriptions.lock().unwrap_or_else(|e| e.into_inner());
+            let Some(subscribers) = subscriptions.get(&connection_id) else {
+                return Some(text);
+            };
+            let topic = serde_json::from_str::<serde_json::Value>(&text)
+                .ok()
+                .and_then(|value| Some(value.get("type")?.as_str()?.split_whitespace().next()?.to_string()));
+            let Some(topic) = topic else {
+                return Some(text);
+            };
+            subscribers.iter().find(|(subscribed, _)| *subscribed == topic).map(|(_, subscriber)| subscriber.clone())
+        };
+        match subscriber {
+            Some(subscriber) => {
+                subscriber(text);
+                None
+            }
+            None => Some(text),
+        }
+    }
+
+    /// Opens a new connection to the URL of `conn`, which the peer closed, and hands its label
+    /// over to it, returning the ID of the new connection.
+    ///
+    /// A failed reconnection is reported to the error handler.
+    async fn reconnect(self: &Arc<Self>, connection_id: usize, conn: &WsConnection) -> Option<usize> {
+        let new_id = match conn.handshake_request() {
+            Ok(request) => self.open(request).await,
+            Err(e) => Err(e),
+        };
+        match new_id {
+            Ok(new_id) => {
+                info!("{}Reconnected connection {} as {}", self.log_prefix(connection_id), connection_id, new_id);
+                let mut labels = self.labels.lock().unwrap_or_else(|e| e.into_inner());
+                if let Some(label) = labels.remove(&connection_id) {
+                    labels.insert(new_id, label);
+                }
+                Some(new_id)
+            }
+            Err(e) => {
+                self.report_error(connection_id, &e);
+                None
+            }
+        }
+    }
+
+    /// Returns the log prefix of a connection, see `WebSocketManager::set_label`.
+    fn log_prefix(&self, connection_id: usize) -> String {
+        log_prefix(&self.labels, connection_id)
+    }
+
+    /// Restarts the idle timer of a connection, if it has one.
+    async fn record_activity(&self, connection_id: usize) {
+        if let Some(idle) = self.idle_timeouts.lock().await.get_mut(&connection_id) {
+            idle.deadline = Instant::now() + idle.timeout;
+        }
+    }
+}
+
+impl SocketIOManager {
+    /// Connects to the default namespace of the Socket.IO server at `url`.
+    pub async fn connect(url: &str) -> Result<Self, ReqlyError> {
+        let socket = socketio_rs::ClientBuilder::new(url).namespace("/").connect().await?;
+        info!("Connected to Socket.IO server at {}", url);
+        Ok(SocketIOManager { socket, middleware: StdMutex::new(Vec::new()) })
+    }
+
+    /// Passes the event name and payload of every later `emit` through `hook`, which returns
+    /// the ones to send instead.
+    ///
+    /// Hooks run in the order they were registered, each receiving the output of the last.
+    pub fn register_middleware(&self, hook: impl Fn(String, Payload) -> (String, Payload) + Send + 'static) {
+        self.middleware.lock().unwrap_or_else(|e| e.into_inner()).push(Box::new(hook));
+    }
+
+    /// Emits `event` with `payload` to the server, after the registered middleware.
+    pub async fn emit(&self, event: &str, payload: Payload) -> Result<(), ReqlyError> {
+        let (event, payload) = self
+            .middleware
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .iter()
+            .fold((event.to_string(), payload), |(event, payload), hook| hook(event, payload));
+        self.socket.emit(event.as_str(), payload).await?;
+        debug!("Emitted Socket.IO event {}", event);
+        Ok(())
+    }
+
+    /// Disconnects from the server.
+    pub async fn disconnect(&self) -> Result<(), ReqlyError> {
+        self.socket.disconnect().await?;
+        Ok(())
+    }
+}
+
+impl Default for WebSocketManager {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
-#[tokio::test]
-async fn test_tcp_connection() {
-    let manager = TcpManager::new();
-    let mut stream = TcpStream::connect("127.0.0.1:5007").await.unwrap();
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::FutureExt;
+    use serde_json::json;
+    use socketio_rs::{ClientBuilder, Payload};
+    use tokio::time::sleep;
+    use tokio_tungstenite::{accept_async, accept_hdr_async};
+
+    /// Starts a local WebSocket echo server that waits `handshake_delay` before each handshake.
+    async fn spawn_echo_server(handshake_delay: Duration) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    sleep(handshake_delay).await;
+                    let mut ws = accept_async(stream).await.unwrap();
+                    while let Some(Ok(msg)) = ws.next().await {
+                        if msg.is_text() || msg.is_binary() {
+                            let _ = ws.send(msg).await;
+                        }
+                    }
+                });
+            }
+        });
+
+        format!("ws://{}", addr)
+    }
+
+    /// Starts a local TCP server that echoes what it reads and closes once the client shuts
+    /// down its write half.
+    async fn spawn_tcp_echo_server() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    let mut buffer = [0; 1024];
+                    while let Ok(len @ 1..) = stream.read(&mut buffer).await {
+                        let _ = stream.write_all(&buffer[..len]).await;
+                    }
+                });
+            }
+        });
+
+        addr.to_string()
+    }
+
+    #[tokio::test]
+    async fn test_websocket_state_transitions() {
+        let url = spawn_echo_server(Duration::from_millis(200)).await;
+        let manager = WebSocketManager::new();
+
+        let (connection_id, ()) = tokio::join!(manager.connect(&url), async {
+            sleep(Duration::from_millis(50)).await;
+            assert_eq!(manager.state(0).await, WsConnectionState::Connecting);
+        });
+        let connection_id = connection_id.unwrap();
+        assert_eq!(manager.state(connection_id).await, WsConnectionState::Open);
+
+        manager.send_message(connection_id, "hello!".to_string()).await.unwrap();
+        assert_eq!(manager.state(connection_id).await, WsConnectionState::Open);
 
-    let message = "hello!".to_string();
-    stream.write_all(message.as_bytes()).await.unwrap();
+        tokio::join!(manager.close_connection(connection_id), async {
+            assert_eq!(manager.state(connection_id).await, WsConnectionState::Closing);
+        });
+        assert_eq!(manager.state(connection_id).await, WsConnectionState::Closed);
+    }
+
+    #[tokio::test]
+    async fn test_websocket_ids_not_reused() {
+        let url = spawn_echo_server(Duration::ZERO).await;
+        let manager = WebSocketManager::new();
+
+        let first = manager.connect(&url).await.unwrap();
+        assert_eq!(first, 0);
+        manager.close_connection(first).await;
+        assert_eq!(manager.state(first).await, WsConnectionState::Closed);
+
+        let second = manager.connect(&url).await.unwrap();
+        assert!(second >= 1);
+        assert_eq!(manager.state(first).await, WsConnectionState::Closed);
+        assert_eq!(manager.state(second).await, WsConnectionState::Open);
+    }
+
+    #[tokio::test]
+    async fn test_websocket_with_connections_concurrent_send() {
+        let url = spawn_echo_server(Duration::ZERO).await;
+        let manager = WebSocketManager::new();
+        let connection_id = manager.connect(&url).await.unwrap();
+
+        let concurrent = async {
+            tokio::join!(
+                manager.send_message(connection_id, "hello!".to_string()),
+                manager.with_connections(|ids| ids.len()),
+                manager.send_message(connection_id, "again!".to_string()),
+                manager.with_connections(|ids| ids.contains(&connection_id)),
+            )
+        };
+        let (first, count, second, found) = tokio::time::timeout(Duration::from_secs(2), concurrent)
+            .await
+            .expect("concurrent calls deadlocked");
+        first.unwrap();
+        second.unwrap();
+        assert_eq!(count, 1);
+        assert!(found);
+    }
+
+    #[tokio::test]
+    async fn test_websocket_send_on_other_connection_not_blocked() {
+        // A server that completes the handshake but never reads, so large sends stall.
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let stalled_url = format!("ws://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let _ws = accept_async(stream).await.unwrap();
+            sleep(Duration::from_secs(60)).await;
+        });
+        let url = spawn_echo_server(Duration::ZERO).await;
+
+        let manager = WebSocketManager::new();
+        let stalled = manager.connect(&stalled_url).await.unwrap();
+        let other = manager.connect(&url).await.unwrap();
+
+        let large = "x".repeat(32 * 1024 * 1024);
+        let sends = async {
+            tokio::select! {
+                _ = manager.send_message(stalled, large) => panic!("send to a stalled peer completed"),
+                result = manager.send_message(other, "hello!".to_string()) => result.unwrap(),
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(2), sends)
+            .await
+            .expect("send was blocked by the stalled connection");
+        let message = manager.receive_message(other, Some(1000)).await.unwrap();
+        assert_eq!(message, Some(WsMessage::Text("hello!".to_string())));
+    }
 
-    let mut buffer = vec![0; 1024];
-    let len = stream.read(&mut buffer).await.unwrap();
-    assert_eq!(&buffer[..len], format!("Server received: {}", message).as_bytes());
+    #[tokio::test]
+    async fn test_websocket_close_awaits_handshake() {
+        let url = spawn_echo_server(Duration::ZERO).await;
+        let manager = WebSocketManager::new();
+        let first = manager.connect(&url).await.unwrap();
+        let second = manager.connect(&url).await.unwrap();
 
-    stream.shutdown().await.unwrap();
-}
\ No newline at end of file
+        manager.close_connection(first).await;
+        assert_eq!(manager.with_connections(|ids| ids.to_vec()).await, vec![second]);
+        manager.close_connection(second).await;
+        assert_eq!(manager.with_connections(|ids| ids.len()).await, 0);
+
+        // Closing an unknown connection returns right away.
+        manager.close_connection(second).await;
+    }
+
+    #[tokio::test]
+    async fn test_websocket_idle_timeout() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+        let (close_sender, mut close_receiver) = mpsc::channel(1);
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut ws = accept_async(stream).await.unwrap();
+            while let Some(Ok(msg)) = ws.next().await {
+                if let Message::Close(frame) = msg {
+                    let _ = close_sender.send(frame.map(|frame| frame.code)).await;
+                }
+            }
+        });
+
+        let manager = WebSocketManager::new();
+        let connection_id = manager.connect(&url).await.unwrap();
+        manager.set_idle_timeout(connection_id, 150).await;
+
+        // Activity before the deadline restarts the timer.
+        sleep(Duration::from_millis(100)).await;
+        manager.send_message(connection_id, "still here".to_string()).await.unwrap();
+        sleep(Duration::from_millis(100)).await;
+        assert_eq!(manager.state(connection_id).await, WsConnectionState::Open);
+
+        let code = tokio::time::timeout(Duration::from_secs(2), close_receiver.recv()).await.unwrap();
+        assert_eq!(code, Some(Some(CloseCode::Normal)));
+        for _ in 0..50 {
+            if manager.state(connection_id).await == WsConnectionState::Closed {
+                break;
+            }
+            sleep(Duration::from_millis(20)).await;
+        }
+        assert_eq!(manager.state(connection_id).await, WsConnectionState::Closed);
+    }
+
+    /// Counts the writes that reach the socket; nothing is ever read from it.
+    struct CountingStream {
+        writes: Arc<AtomicUsize>,
+    }
+
+    impl AsyncRead for CountingStream {
+        fn poll_read(self: Pin<&mut Self>, _: &mut Context<'_>, _: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+            Poll::Pending
+        }
+    }
+
+    impl AsyncWrite for CountingStream {
+        fn poll_write(self: Pin<&mut Self>, _: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+            self.writes.fetch_add(1, Ordering::SeqCst);
+            Poll::Ready(Ok(buf.len()))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_batched_stream() {
+        let writes = Arc::new(AtomicUsize::new(0));
+        let batching = Arc::new(AtomicBool::new(false));
+        let stream = BatchedStream::new(CountingStream { writes: writes.clone() }, batching.clone());
+        let mut ws = WebSocketStream::from_raw_socket(stream, tokio_tungstenite::tungstenite::protocol::Role::Client, None).await;
+
+        // Unbatched, tungstenite writes every frame as it is fed.
+        for i in 0..10 {
+            ws.feed(Message::Text(format!("message {}", i))).await.unwrap();
+        }
+        ws.flush().await.unwrap();
+        assert_eq!(writes.swap(0, Ordering::SeqCst), 10);
+
+        // Batched, the frames stay buffered until the flag is cleared and the stream flushed.
+        batching.store(true, Ordering::SeqCst);
+        for i in 0..10 {
+            ws.feed(Message::Text(format!("message {}", i))).await.unwrap();
+        }
+        ws.flush().await.unwrap();
+        assert_eq!(writes.load(Ordering::SeqCst), 0);
+        batching.store(false, Ordering::SeqCst);
+        ws.flush().await.unwrap();
+        assert_eq!(writes.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_websocket_send_many() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+        let server = std::thread::spawn(move || {
+            tokio::runtime::Runtime::new().unwrap().block_on(async move {
+                listener.set_nonblocking(true).unwrap();
+                let listener = TcpListener::from_std(listener).unwrap();
+                let (stream, _) = listener.accept().await.unwrap();
+                let mut ws = accept_async(stream).await.unwrap();
+                let mut received = Vec::new();
+                while let Some(Ok(Message::Text(text))) = ws.next().await {
+                    received.push(text);
+                }
+                received
+            })
+        });
+
+        let manager = WebSocketManager::new();
+        let connection_id = manager.connect(&url).await.unwrap();
+        let messages: Vec<String> = (0..100).map(|i| format!("message {}", i)).collect();
+        manager.send_many(connection_id, messages.clone()).await.unwrap();
+        manager.send_message(connection_id, "after".to_string()).await.unwrap();
+
+        manager.close_connection(connection_id).await;
+        let received = server.join().unwrap();
+        assert_eq!(received[..100], messages[..]);
+        assert_eq!(received[100..], ["after".to_string()]);
+        assert!(matches!(
+            manager.send_many(connection_id, vec!["lost".to_string()]).await,
+            Err(ReqlyError::ConnectionNotFound(_))
+        ));
+    }
+
+    /// Waits until the manager holds `count` connections.
+    async fn wait_for_connection_count(manager: &WebSocketManager, count: usize) {
+        for _ in 0..50 {
+            if manager.connection_count().await == count {
+                return;
+            }
+            sleep(Duration::from_millis(20)).await;
+        }
+        assert_eq!(manager.connection_count().await, count);
+    }
+
+    #[tokio::test]
+    async fn test_websocket_removes_connection_closed_by_peer() {
+        // The first connection is closed right away, the second after a message.
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            for farewell in [None, Some("bye")] {
+                let (stream, _) = listener.accept().await.unwrap();
+                let mut ws = accept_async(stream).await.unwrap();
+                if let Some(farewell) = farewell {
+                    ws.send(Message::Text(farewell.to_string())).await.unwrap();
+                }
+                ws.close(None).await.unwrap();
+                while ws.next().await.is_some() {}
+            }
+        });
+
+        let manager = WebSocketManager::new();
+        let connection_id = manager.connect(&url).await.unwrap();
+        // Noticed without anyone receiving on the connection.
+        wait_for_connection_count(&manager, 0).await;
+        assert_eq!(manager.state(connection_id).await, WsConnectionState::Closed);
+        let result = manager.send_message(connection_id, "lost".to_string()).await;
+        assert!(matches!(result.unwrap_err().downcast_ref(), Some(ReqlyError::ConnectionNotFound(_))));
+        assert!(matches!(
+            manager.receive_message(connection_id, Some(100)).await,
+            Err(ReqlyError::ConnectionNotFound(_))
+        ));
+
+        // A message received before the close is kept until it was received.
+        let connection_id = manager.connect(&url).await.unwrap();
+        wait_for_connection_count(&manager, 0).await;
+        let received = manager.receive_message(connection_id, Some(1000)).await.unwrap();
+        assert_eq!(received, Some(WsMessage::Text("bye".to_string())));
+        assert_eq!(manager.receive_message(connection_id, Some(1000)).await.unwrap(), None);
+        assert!(matches!(
+            manager.receive_message(connection_id, Some(100)).await,
+            Err(ReqlyError::ConnectionNotFound(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_websocket_on_reconnect() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            // The first connection is closed by the server, the second one echoes.
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut ws = accept_async(stream).await.unwrap();
+            ws.close(None).await.unwrap();
+            while ws.next().await.is_some() {}
+
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut ws = accept_async(stream).await.unwrap();
+            while let Some(Ok(msg)) = ws.next().await {
+                if msg.is_text() {
+                    let _ = ws.send(msg).await;
+                }
+            }
+        });
+
+        let manager = WebSocketManager::new();
+        let connection_id = manager.connect(&url).await.unwrap();
+        let (reconnected, mut new_ids) = mpsc::unbounded_channel();
+        manager.on_reconnect(connection_id, move |new_id| {
+            let _ = reconnected.send(new_id);
+        });
+
+        let new_id = tokio::time::timeout(Duration::from_secs(1), new_ids.recv()).await.unwrap().unwrap();
+        assert_ne!(new_id, connection_id);
+        assert_eq!(manager.state(connection_id).await, WsConnectionState::Closed);
+        assert_eq!(manager.state(new_id).await, WsConnectionState::Open);
+        assert_eq!(manager.connection_count().await, 1);
+
+        manager.send_message(new_id, "resubscribe".to_string()).await.unwrap();
+        let received = manager.receive_message(new_id, Some(1000)).await.unwrap();
+        assert_eq!(received, Some(WsMessage::Text("resubscribe".to_string())));
+
+        // An explicit close does not reconnect.
+        manager.close_connection(new_id).await;
+        assert_eq!(manager.connection_count().await, 0);
+        assert!(new_ids.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_websocket_subscribe() {
+        let url = spawn_echo_server(Duration::ZERO).await;
+        let manager = WebSocketManager::new();
+        let connection_id = manager.connect(&url).await.unwrap();
+        let (chat_sender, mut chat) = mpsc::unbounded_channel();
+        let (presence_sender, mut presence) = mpsc::unbounded_channel();
+        manager.subscribe(connection_id, "chat", move |text| {
+            let _ = chat_sender.send(text);
+        });
+        manager.subscribe(connection_id, "presence", move |text| {
+            let _ = presence_sender.send(text);
+        });
+
+        let message = r#"{"type":"chat","body":"hi"}"#;
+        manager.send_message(connection_id, message.to_string()).await.unwrap();
+        manager.send_message(connection_id, r#"{"type":"chatter"}"#.to_string()).await.unwrap();
+        manager.send_message(connection_id, "not json".to_string()).await.unwrap();
+
+        // The chat message is routed, the others fall through in order.
+        let received = manager.receive_message(connection_id, Some(1000)).await.unwrap();
+        assert_eq!(received, Some(WsMessage::Text(r#"{"type":"chatter"}"#.to_string())));
+        let received = manager.receive_message(connection_id, Some(1000)).await.unwrap();
+        assert_eq!(received, Some(WsMessage::Text("not json".to_string())));
+        assert_eq!(chat.try_recv().unwrap(), message);
+        assert!(chat.try_recv().is_err());
+        assert!(presence.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_websocket_subscriber_subscribes() {
+        let url = spawn_echo_server(Duration::ZERO).await;
+        let manager = Arc::new(WebSocketManager::new());
+        let connection_id = manager.connect(&url).await.unwrap();
+        let (sender, mut routed) = mpsc::unbounded_channel();
+
+        // The first chat message replaces the chat subscriber from within its callback.
+        let weak = Arc::downgrade(&manager);
+        let first_sender = sender.clone();
+        manager.subscribe(connection_id, "chat", move |text| {
+            let _ = first_sender.send(format!("first: {}", text));
+            let sender = first_sender.clone();
+            if let Some(manager) = weak.upgrade() {
+                manager.subscribe(connection_id, "chat", move |text| {
+                    let _ = sender.send(format!("second: {}", text));
+                });
+            }
+        });
+        drop(sender);
+
+        // Routing does not wait for receive_message.
+        for body in ["a", "b"] {
+            let message = json!({ "type": "chat", "body": body }).to_string();
+            manager.send_message(connection_id, message).await.unwrap();
+        }
+        for expected in [r#"first: {"body":"a","type":"chat"}"#, r#"second: {"body":"b","type":"chat"}"#] {
+            let text = tokio::time::timeout(Duration::from_secs(1), routed.recv()).await.unwrap().unwrap();
+            assert_eq!(text, expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_websocket_ordered_delivery_skips_missing_message() {
+        // Once the client sent something, sends every numbered message but the first, then
+        // the first one late.
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut ws = accept_async(stream).await.unwrap();
+            ws.next().await.unwrap().unwrap();
+            for seq in 1..=MAX_HELD_MESSAGES + 1 {
+                let envelope = json!({ "seq": seq, "payload": format!("message {}", seq) });
+                ws.send(Message::Text(envelope.to_string())).await.unwrap();
+            }
+            ws.send(Message::Text(json!({ "seq": 0, "payload": "late" }).to_string())).await.unwrap();
+            ws.send(Message::Text("done".to_string())).await.unwrap();
+            sleep(Duration::from_secs(60)).await;
+        });
+
+        let manager = WebSocketManager::new();
+        let connection_id = manager.connect(&url).await.unwrap();
+        manager.enable_ordered_delivery(connection_id);
+        manager.send_message(connection_id, "start".to_string()).await.unwrap();
+
+        for seq in 1..=MAX_HELD_MESSAGES + 1 {
+            let message = manager.receive_message(connection_id, Some(1000)).await.unwrap();
+            assert_eq!(message, Some(WsMessage::Text(format!("message {}", seq))));
+        }
+        let message = manager.receive_message(connection_id, Some(1000)).await.unwrap();
+        assert_eq!(message, Some(WsMessage::Text("done".to_string())));
+    }
+
+    #[tokio::test]
+    async fn test_websocket_ordered_delivery() {
+        // Echoes the messages in batches of four, each batch in reverse order, and records
+        // the order in which the payloads were sent.
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+        let sent = Arc::new(StdMutex::new(Vec::new()));
+        let recorded = sent.clone();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut ws = accept_async(stream).await.unwrap();
+            let mut batch = Vec::new();
+            while let Some(Ok(msg)) = ws.next().await {
+                if let Message::Text(text) = &msg {
+                    let envelope: serde_json::Value = serde_json::from_str(text).unwrap();
+                    assert_eq!(envelope["seq"], recorded.lock().unwrap().len());
+                    recorded.lock().unwrap().push(envelope["payload"].as_str().unwrap().to_string());
+                    batch.push(msg);
+                }
+                if batch.len() == 4 {
+                    for msg in batch.drain(..).rev() {
+                        ws.send(msg).await.unwrap();
+                    }
+                }
+            }
+        });
+
+        let manager = Arc::new(WebSocketManager::new());
+        let connection_id = manager.connect(&url).await.unwrap();
+        manager.enable_ordered_delivery(connection_id);
+
+        // Two tasks send at the same time; the sequence numbers follow the order on the wire.
+        let sends = (0..2).map(|task| {
+            let manager = manager.clone();
+            tokio::spawn(async move {
+                for i in 0..4 {
+                    manager.send_message(connection_id, format!("task {} message {}", task, i)).await.unwrap();
+                    tokio::task::yield_now().await;
+                }
+            })
+        });
+        for send in futures::future::join_all(sends).await {
+            send.unwrap();
+        }
+        manager.send_many(connection_id, (0..4).map(|i| format!("batch {}", i)).collect()).await.unwrap();
+
+        let mut received = Vec::new();
+        for _ in 0..12 {
+            match manager.receive_message(connection_id, Some(1000)).await.unwrap() {
+                Some(WsMessage::Text(text)) => received.push(text),
+                other => panic!("unexpected message: {:?}", other),
+            }
+        }
+        assert_eq!(received, *sent.lock().unwrap());
+        // Each task's messages keep their order, and the batch comes last, in order.
+        for task in 0..2 {
+            let prefix = format!("task {} ", task);
+            let messages: Vec<_> = received.iter().filter(|text| text.starts_with(&prefix)).cloned().collect();
+            let expected: Vec<_> = (0..4).map(|i| format!("task {} message {}", task, i)).collect();
+            assert_eq!(messages, expected);
+        }
+        assert_eq!(received[8..], ["batch 0", "batch 1", "batch 2", "batch 3"]);
+    }
+
+    #[tokio::test]
+    async fn test_websocket_deduplication() {
+        let url = spawn_echo_server(Duration::ZERO).await;
+        let manager = WebSocketManager::new();
+        let connection_id = manager.connect(&url).await.unwrap();
+        manager.enable_deduplication(connection_id, 2);
+        let (calls_sender, mut calls) = mpsc::unbounded_channel();
+        manager.subscribe(connection_id, "tick", move |text| {
+            let _ = calls_sender.send(text);
+        });
+
+        let tick = r#"{"type":"tick","n":1}"#.to_string();
+        for _ in 0..3 {
+            manager.send_message(connection_id, tick.clone()).await.unwrap();
+        }
+        // With a window of two, "a" and "b" push the tick out of it, so the last tick is
+        // delivered again, and the tick then pushes out the first "a".
+        for message in ["a", "a", "b", &tick, "a"] {
+            manager.send_message(connection_id, message.to_string()).await.unwrap();
+        }
+
+        let mut received = Vec::new();
+        for _ in 0..3 {
+            received.push(manager.receive_message(connection_id, Some(1000)).await.unwrap().unwrap());
+        }
+        let text = |text: &str| WsMessage::Text(text.to_string());
+        assert_eq!(received, [text("a"), text("b"), text("a")]);
+        assert!(matches!(manager.receive_message(connection_id, Some(200)).await, Err(ReqlyError::Timeout)));
+        assert_eq!(calls.try_recv().unwrap(), tick);
+        assert_eq!(calls.try_recv().unwrap(), tick);
+        assert!(calls.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_websocket_json_rpc_call() {
+        // Answers `subtract` calls in pairs, the second call first, with a notification before
+        // them, and fails any other method.
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut ws = accept_async(stream).await.unwrap();
+            let mut calls = Vec::new();
+            while let Some(Ok(Message::Text(text))) = ws.next().await {
+                let request: serde_json::Value = serde_json::from_str(&text).unwrap();
+                assert_eq!(request["jsonrpc"], "2.0");
+                if request["method"] != "subtract" {
+                    let error = json!({ "jsonrpc": "2.0", "id": request["id"], "error": { "code": -32601, "message": "Method not found" } });
+                    ws.send(Message::Text(error.to_string())).await.unwrap();
+                    continue;
+                }
+                calls.push(request);
+                if calls.len() == 2 {
+                    let notification = json!({ "jsonrpc": "2.0", "method": "update", "params": [1] });
+                    ws.send(Message::Text(notification.to_string())).await.unwrap();
+                    for call in calls.drain(..).rev() {
+                        let result = call["params"][0].as_i64().unwrap() - call["params"][1].as_i64().unwrap();
+                        let response = json!({ "jsonrpc": "2.0", "id": call["id"], "result": result });
+                        ws.send(Message::Text(response.to_string())).await.unwrap();
+                    }
+                }
+            }
+        });
+
+        let manager = WebSocketManager::new();
+        let connection_id = manager.connect(&url).await.unwrap();
+        // The server only answers requests sent without a sequence envelope.
+        manager.enable_ordered_delivery(connection_id);
+        let (first, second) = tokio::join!(
+            manager.json_rpc_call(connection_id, "subtract", json!([42, 23]), 1000),
+            manager.json_rpc_call(connection_id, "subtract", json!([23, 42]), 1000),
+        );
+        assert_eq!(first.unwrap(), json!(19));
+        assert_eq!(second.unwrap(), json!(-19));
+
+        let received = manager.receive_message(connection_id, Some(1000)).await.unwrap();
+        let notification = json!({ "jsonrpc": "2.0", "method": "update", "params": [1] });
+        assert_eq!(received, Some(WsMessage::Text(notification.to_string())));
+
+        let result = manager.json_rpc_call(connection_id, "divide", json!([1, 0]), 1000).await;
+        assert!(matches!(
+            result,
+            Err(ReqlyError::JsonRpc { code: -32601, message, data: None }) if message == "Method not found"
+        ));
+        // A lone `subtract` call is never answered.
+        let result = manager.json_rpc_call(connection_id, "subtract", json!([1, 1]), 200).await;
+        assert!(matches!(result, Err(ReqlyError::Timeout)));
+    }
+
+    #[tokio::test]
+    async fn test_websocket_error_handler() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            for _ in 0..2 {
+                let (stream, _) = listener.accept().await.unwrap();
+                let ws = accept_async(stream).await.unwrap();
+                tokio::spawn(async move {
+                    let (_, mut read) = ws.split();
+                    // Drops the connection without answering the close frame.
+                    while let Some(Ok(msg)) = read.next().await {
+                        if msg.is_close() {
+                            break;
+                        }
+                    }
+                });
+            }
+            // Drops the third connection right after the handshake.
+            let (stream, _) = listener.accept().await.unwrap();
+            drop(accept_async(stream).await.unwrap());
+        });
+
+        let manager = WebSocketManager::new();
+        let (errors, mut reported) = mpsc::unbounded_channel();
+        manager.set_error_handler(move |id, error| {
+            let _ = errors.send((id, matches!(error, ReqlyError::WebSocket(_))));
+        });
+        let healthy = manager.connect(&url).await.unwrap();
+        let failing = manager.connect(&url).await.unwrap();
+
+        manager.close_connection(failing).await;
+        let report = tokio::time::timeout(Duration::from_secs(2), reported.recv()).await.unwrap();
+        assert_eq!(report, Some((failing, true)));
+        assert_eq!(manager.state(healthy).await, WsConnectionState::Open);
+        assert!(reported.try_recv().is_err());
+
+        // A read error is reported without anyone receiving on the connection.
+        let dropped = manager.connect(&url).await.unwrap();
+        let report = tokio::time::timeout(Duration::from_secs(2), reported.recv()).await.unwrap();
+        assert_eq!(report, Some((dropped, true)));
+        assert_eq!(manager.state(healthy).await, WsConnectionState::Open);
+    }
+
+    #[tokio::test]
+    async fn test_websocket_receive_buffer_cap() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut ws = accept_async(stream).await.unwrap();
+            for i in 0..1000 {
+                ws.feed(Message::Text(format!("message {}", i))).await.unwrap();
+            }
+            ws.flush().await.unwrap();
+            while ws.next().await.is_some() {}
+        });
+
+        let manager = WebSocketManager::new();
+        let connection_id = manager.connect(&url).await.unwrap();
+        manager.set_receive_buffer_cap(connection_id, 10).await;
+        sleep(Duration::from_millis(100)).await;
+
+        let conn = manager.connection(connection_id).await.unwrap();
+        assert_eq!(conn.inbound.len(), 10);
+        for i in 0..1000 {
+            let message = manager.receive_message(connection_id, Some(1000)).await.unwrap();
+            assert_eq!(message, Some(WsMessage::Text(format!("message {}", i))));
+            assert!(conn.inbound.len() <= 10);
+        }
+        let result = manager.receive_message(connection_id, Some(50)).await;
+        assert!(matches!(result, Err(ReqlyError::Timeout)));
+    }
+
+    #[tokio::test]
+    async fn test_websocket_shutdown_all() {
+        let url = spawn_echo_server(Duration::ZERO).await;
+        let manager = WebSocketManager::new();
+        let mut ids = Vec::new();
+        for _ in 0..3 {
+            ids.push(manager.connect(&url).await.unwrap());
+        }
+        assert_eq!(manager.connection_count().await, 3);
+
+        manager.shutdown_all().await.unwrap();
+        for id in ids {
+            assert_eq!(manager.state(id).await, WsConnectionState::Closed);
+        }
+        assert_eq!(manager.connection_count().await, 0);
+        manager.shutdown_all().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_websocket_closes_run_concurrently() {
+        // A server that completes the handshake but never answers the close frame.
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let unresponsive_url = format!("ws://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    let _ws = accept_async(stream).await.unwrap();
+                    sleep(Duration::from_secs(60)).await;
+                });
+            }
+        });
+        let url = spawn_echo_server(Duration::ZERO).await;
+
+        let manager = WebSocketManager::new();
+        manager.connect(&unresponsive_url).await.unwrap();
+        manager.connect(&unresponsive_url).await.unwrap();
+
+        // Both unresponsive peers use up the handshake timeout at the same time, and a close
+        // requested meanwhile is not held up by them.
+        let start = Instant::now();
+        let (result, ()) = tokio::join!(manager.shutdown_all(), async {
+            sleep(Duration::from_millis(100)).await;
+            let healthy = manager.connect(&url).await.unwrap();
+            tokio::time::timeout(Duration::from_secs(1), manager.close_connection(healthy))
+                .await
+                .expect("close was blocked by an unresponsive peer");
+        });
+        result.unwrap();
+        assert!(start.elapsed() < CLOSE_HANDSHAKE_TIMEOUT * 3 / 2);
+        assert_eq!(manager.connection_count().await, 0);
+    }
+
+    #[tokio::test]
+    async fn test_websocket_measure_latency() {
+        let url = spawn_echo_server(Duration::ZERO).await;
+        let manager = WebSocketManager::new();
+        let connection_id = manager.connect(&url).await.unwrap();
+
+        // The echo arrives before the pong and must still reach `receive_message`, even after
+        // a receive buffer cap is set, ahead of the messages arriving later.
+        manager.send_message(connection_id, "before".to_string()).await.unwrap();
+        let latency = manager.measure_latency(connection_id).await.unwrap();
+        assert!(latency > Duration::ZERO && latency < Duration::from_secs(1), "latency: {:?}", latency);
+        manager.set_receive_buffer_cap(connection_id, 4).await;
+        manager.send_message(connection_id, "after".to_string()).await.unwrap();
+        let latency = manager.measure_latency(connection_id).await.unwrap();
+        assert!(latency > Duration::ZERO && latency < Duration::from_secs(1), "latency: {:?}", latency);
+
+        for expected in ["before", "after"] {
+            let received = manager.receive_message(connection_id, Some(1000)).await.unwrap();
+            assert_eq!(received, Some(WsMessage::Text(expected.to_string())));
+        }
+
+        assert!(matches!(
+            manager.measure_latency(connection_id + 1).await,
+            Err(ReqlyError::ConnectionNotFound(_))
+        ));
+    }
+
+    /// Records the log messages of all tests, for the tests asserting on them.
+    struct CapturingLogger;
+
+    static CAPTURED_LOGS: StdMutex<Vec<String>> = StdMutex::new(Vec::new());
+
+    impl log::Log for CapturingLogger {
+        fn enabled(&self, _: &log::Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &log::Record) {
+            CAPTURED_LOGS.lock().unwrap().push(record.args().to_string());
+        }
+
+        fn flush(&self) {}
+    }
+
+    fn capture_logs() {
+        static LOGGER: std::sync::Once = std::sync::Once::new();
+        LOGGER.call_once(|| {
+            log::set_logger(&CapturingLogger).unwrap();
+            log::set_max_level(log::LevelFilter::Debug);
+        });
+    }
+
+    #[tokio::test]
+    async fn test_websocket_label() {
+        capture_logs();
+        let url = spawn_echo_server(Duration::ZERO).await;
+        let manager = WebSocketManager::new();
+        let connection_id = manager.connect(&url).await.unwrap();
+        manager.set_label(connection_id, "chat-server");
+
+        manager.send_message(connection_id, "hello".to_string()).await.unwrap();
+        manager.receive_message(connection_id, Some(1000)).await.unwrap();
+        manager.close_connection(connection_id).await;
+
+        let logs: Vec<String> = CAPTURED_LOGS
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|message| message.starts_with("[ws:chat-server] "))
+            .cloned()
+            .collect();
+        let expected = [
+            format!("[ws:chat-server] Sent message to connection {}", connection_id),
+            format!("[ws:chat-server] Received message on connection {}", connection_id),
+            format!("[ws:chat-server] Closed connection {}", connection_id),
+        ];
+        for message in &expected {
+            assert!(logs.contains(message), "missing {:?} in {:?}", message, logs);
+        }
+        assert!(manager.shared.labels.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_websocket_json() {
+        #[derive(Debug, PartialEq, Serialize, Deserialize)]
+        struct Event {
+            id: u32,
+            name: String,
+            tags: Vec<String>,
+        }
+
+        let url = spawn_echo_server(Duration::ZERO).await;
+        let manager = WebSocketManager::new();
+        let connection_id = manager.connect(&url).await.unwrap();
+        let event = Event { id: 7, name: "deploy".to_string(), tags: vec!["prod".to_string()] };
+
+        manager.send_json(connection_id, &event).await.unwrap();
+        let received: Event = manager.receive_json(connection_id, Some(1000)).await.unwrap();
+        assert_eq!(received, event);
+
+        manager.send_message(connection_id, "not json".to_string()).await.unwrap();
+        let result = manager.receive_json::<Event>(connection_id, Some(1000)).await;
+        assert!(matches!(result, Err(ReqlyError::Json(_))));
+        assert!(matches!(
+            manager.send_json(connection_id + 1, &event).await,
+            Err(ReqlyError::ConnectionNotFound(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_websocket_receive_message() {
+        let url = spawn_echo_server(Duration::ZERO).await;
+        let manager = WebSocketManager::new();
+        let connection_id = manager.connect(&url).await.unwrap();
+
+        manager.send_message(connection_id, "hello!".to_string()).await.unwrap();
+        let message = manager.receive_message(connection_id, Some(1000)).await.unwrap();
+        assert_eq!(message, Some(WsMessage::Text("hello!".to_string())));
+
+        let result = manager.receive_message(connection_id, Some(50)).await;
+        assert!(matches!(result, Err(ReqlyError::Timeout)));
+
+        assert_eq!(
+            serde_json::to_string(&WsMessage::Text("hi".to_string())).unwrap(),
+            r#"{"type":"text","data":"hi"}"#
+        );
+    }
+
+    #[tokio::test]
+    async fn test_websocket_command_queue_full() {
+        let manager = WebSocketManager::new();
+
+        // The background task cannot drain the queue until this test yields.
+        for id in 0..COMMAND_QUEUE_CAPACITY {
+            manager.try_close_connection(id).await.unwrap();
+        }
+        assert_eq!(manager.pending_commands(), COMMAND_QUEUE_CAPACITY);
+        let result = manager.try_close_connection(COMMAND_QUEUE_CAPACITY).await;
+        assert!(matches!(result, Err(ReqlyError::QueueFull)));
+
+        sleep(Duration::from_millis(50)).await;
+        assert_eq!(manager.pending_commands(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_websocket_connect_with_headers() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (seen_sender, mut seen_receiver) = mpsc::channel(1);
+
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            // The callback signature, including its large error type, is dictated by tungstenite.
+            #[allow(clippy::result_large_err)]
+            let callback = |request: &Request, response| {
+                let header = request
+                    .headers()
+                    .get("X-Api-Key")
+                    .map(|value| value.to_str().unwrap().to_string());
+                seen_sender.try_send(header).unwrap();
+                Ok(response)
+            };
+            let mut ws = accept_hdr_async(stream, callback).await.unwrap();
+            while ws.next().await.is_some() {}
+        });
+
+        let manager = WebSocketManager::new();
+        let url = format!("ws://{}", addr);
+        let connection_id = manager
+            .connect_with_headers(&url, &[("X-Api-Key", "secret"), ("Cookie", "session=1")])
+            .await
+            .unwrap();
+
+        assert_eq!(seen_receiver.recv().await.unwrap(), Some("secret".to_string()));
+        assert_eq!(manager.state(connection_id).await, WsConnectionState::Open);
+
+        let result = manager.connect_with_headers(&url, &[("Bad Header", "value")]).await;
+        assert!(matches!(result, Err(ReqlyError::InvalidHeader(_))));
+    }
+
+    #[tokio::test]
+    async fn test_websocket_send_rate_limit() {
+        let url = spawn_echo_server(Duration::ZERO).await;
+        let manager = WebSocketManager::new();
+        let connection_id = manager.connect(&url).await.unwrap();
+        manager.set_send_rate_limit(connection_id, 5.0).await;
+
+        let start = Instant::now();
+        for i in 0..10 {
+            manager.send_message(connection_id, format!("message {}", i)).await.unwrap();
+        }
+        assert!(start.elapsed() >= Duration::from_millis(1800));
+
+        manager.set_send_rate_limit(connection_id, 0.0).await;
+        let start = Instant::now();
+        for i in 0..10 {
+            manager.send_message(connection_id, format!("message {}", i)).await.unwrap();
+        }
+        assert!(start.elapsed() < Duration::from_millis(500));
+
+        // Each message of a batch takes a slot, delaying the batch and the sends after it.
+        manager.set_send_rate_limit(connection_id, 20.0).await;
+        let start = Instant::now();
+        let batch = (0..10).map(|i| format!("batch {}", i)).collect();
+        manager.send_many(connection_id, batch).await.unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(450), "batch sent after {:?}", start.elapsed());
+        manager.send_message(connection_id, "after".to_string()).await.unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(500), "message sent after {:?}", start.elapsed());
+
+        // Closing the connection forgets its limit.
+        manager.set_send_rate_limit(connection_id, 5.0).await;
+        manager.close_connection(connection_id).await;
+        assert!(manager.shared.rate_limits.lock().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_websocket_connection() {
+        let manager = WebSocketManager::new();
+        let connection_id = manager.connect("ws://localhost:8765").await.unwrap();
+
+        let message = "hello!".to_string();
+        manager.send_message(connection_id, message.clone()).await.unwrap();
+
+        sleep(Duration::from_millis(100)).await;
+
+        let msg = manager.receive_message(connection_id, None).await.unwrap();
+
+        assert_eq!(msg, Some(WsMessage::Text(message)));
+
+        manager.close_connection(connection_id).await;
+        assert_eq!(manager.with_connections(|ids| ids.len()).await, 0);
+    }
+
+    #[tokio::test]
+    async fn test_socketio_connection() {
+        let socket = ClientBuilder::new("http://localhost:5001")
+            .namespace("/")
+            .on("message", |payload: Option<Payload>, socket, _| {
+                async move {
+                    match payload {
+                        Some(Payload::Json(msg)) => {
+                            if let Some(msg) = msg.as_str() {
+                                println!("Received message: {}", msg);
+                                socket.emit("message", Payload::Json(json!(format!("Client received: {}", msg)))).await.unwrap();
+                            } else {
+                                println!("Received unexpected JSON payload");
+                            }
+                        }
+                        _ => println!("Received unexpected payload"),
+                    }
+                }
+                .boxed()
+            })
+            .connect()
+            .await
+            .expect("Failed to connect");
+
+        socket.emit("message", Payload::Json(json!("hello!"))).await.unwrap();
+
+        sleep(Duration::from_millis(100)).await;
+
+        socket.disconnect().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_socketio_middleware() {
+        let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let (received, mut events) = mpsc::channel(4);
+        let server = socketio_rs::ServerBuilder::new(port)
+            .on("/", "message.v2", move |payload: Option<Payload>, _, _| {
+                let received = received.clone();
+                async move {
+                    let _ = received.send(payload).await;
+                }
+                .boxed()
+            })
+            .build();
+        tokio::spawn(async move { server.serve().await });
+
+        let url = format!("http://127.0.0.1:{}", port);
+        let mut manager = None;
+        for _ in 0..50 {
+            match SocketIOManager::connect(&url).await {
+                Ok(connected) => {
+                    manager = Some(connected);
+                    break;
+                }
+                Err(_) => sleep(Duration::from_millis(20)).await,
+            }
+        }
+        let manager = manager.expect("Failed to connect");
+
+        manager.register_middleware(|event, payload| {
+            let Payload::Json(data) = payload else {
+                return (event, payload);
+            };
+            (event, Payload::Json(json!({ "data": data, "timestamp": 1700000000 })))
+        });
+        manager.register_middleware(|event, payload| (format!("{}.v2", event), payload));
+        manager.emit("message", Payload::Json(json!("hello"))).await.unwrap();
+
+        let payload = tokio::time::timeout(Duration::from_secs(5), events.recv()).await.unwrap().unwrap();
+        assert_eq!(payload, Some(Payload::Json(json!({ "data": "hello", "timestamp": 1700000000 }))));
+        manager.disconnect().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_udp_connection() {
+        let manager = UdpManager::new("127.0.0.1:0").await.unwrap();
+
+        let message = "hello!".to_string();
+        manager.send_message(message.as_bytes().to_vec(), "127.0.0.1:5006").await.unwrap();
+
+        sleep(Duration::from_millis(100)).await;
+
+        let (received, _) = manager.receive_message().await.unwrap();
+        assert_eq!(received, format!("Server received: {}", message).as_bytes().to_vec());
+    }
+
+    #[tokio::test]
+    async fn test_udp_new_v6() {
+        let manager = UdpManager::new_v6("[::1]:0").await.unwrap();
+        let addr = manager.socket.local_addr().unwrap();
+        assert!(addr.is_ipv6());
+
+        manager.send_message(b"over ipv6".to_vec(), &addr.to_string()).await.unwrap();
+        let (received, from) = manager.receive_message().await.unwrap();
+        assert_eq!(received, b"over ipv6");
+        assert_eq!(from, addr.to_string());
+
+        let result = UdpManager::new_v6("127.0.0.1:0").await;
+        assert!(matches!(result, Err(ReqlyError::Io(e)) if e.kind() == io::ErrorKind::InvalidInput));
+    }
+
+    #[tokio::test]
+    async fn test_udp_drop_rate() {
+        let sender = UdpManager::new("127.0.0.1:0").await.unwrap();
+        let receiver = UdpManager::new("127.0.0.1:0").await.unwrap();
+        let addr = receiver.socket.local_addr().unwrap().to_string();
+        sender.set_drop_rate(0.5);
+
+        for i in 0..100u8 {
+            sender.send_message(vec![i], &addr).await.unwrap();
+        }
+        let mut received = 0;
+        while tokio::time::timeout(Duration::from_millis(200), receiver.receive_message()).await.is_ok() {
+            received += 1;
+        }
+        // Five standard deviations around the expected 50, so the test practically never fails.
+        assert!((25..=75).contains(&received), "received {} of 100 packets", received);
+
+        sender.set_drop_rate(0.0);
+        sender.send_message(b"kept".to_vec(), &addr).await.unwrap();
+        assert_eq!(receiver.receive_message().await.unwrap().0, b"kept");
+    }
+
+    #[tokio::test]
+    async fn test_udp_receive_from() {
+        let manager = UdpManager::new("127.0.0.1:0").await.unwrap();
+        let addr = manager.socket.local_addr().unwrap();
+        let expected = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let other = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let expected_addr = expected.local_addr().unwrap().to_string();
+
+        other.send_to(b"noise", addr).await.unwrap();
+        expected.send_to(b"wanted", addr).await.unwrap();
+        other.send_to(b"more noise", addr).await.unwrap();
+        assert_eq!(manager.receive_from(&expected_addr, Some(1000)).await.unwrap(), b"wanted");
+
+        // The remaining packet comes from the other sender and is discarded as well.
+        let result = manager.receive_from(&expected_addr, Some(200)).await;
+        assert!(matches!(result, Err(ReqlyError::Timeout)));
+        expected.send_to(b"again", addr).await.unwrap();
+        assert_eq!(manager.receive_from(&expected_addr, None).await.unwrap(), b"again");
+    }
+
+    #[tokio::test]
+    async fn test_udp_receive_exact() {
+        let manager = UdpManager::new("127.0.0.1:0").await.unwrap();
+        let addr = manager.socket.local_addr().unwrap();
+        let peer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+
+        let mut request = [0; 48];
+        request[0] = 0x1b;
+        peer.send_to(&request, addr).await.unwrap();
+        let (packet, from) = manager.receive_exact(48, Some(1000)).await.unwrap();
+        assert_eq!(packet, request);
+        assert_eq!(from, peer.local_addr().unwrap().to_string());
+
+        peer.send_to(&request[..47], addr).await.unwrap();
+        let result = manager.receive_exact(48, Some(1000)).await;
+        assert!(matches!(result, Err(ReqlyError::Io(e)) if e.kind() == io::ErrorKind::InvalidData));
+
+        let result = manager.receive_exact(48, Some(50)).await;
+        assert!(matches!(result, Err(ReqlyError::Timeout)));
+    }
+
+    /// Writes a self-signed certificate for `localhost` and its key as PEM files.
+    fn self_signed_certificate() -> (std::path::PathBuf, std::path::PathBuf) {
+        use openssl::asn1::Asn1Time;
+        use openssl::bn::BigNum;
+        use openssl::ec::{EcGroup, EcKey};
+        use openssl::hash::MessageDigest;
+        use openssl::nid::Nid;
+        use openssl::pkey::PKey;
+        use openssl::x509::extension::BasicConstraints;
+        use openssl::x509::{X509NameBuilder, X509};
+
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        let key = PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap();
+        let mut name = X509NameBuilder::new().unwrap();
+        name.append_entry_by_nid(Nid::COMMONNAME, "localhost").unwrap();
+        let name = name.build();
+
+        let mut builder = X509::builder().unwrap();
+        builder.set_version(2).unwrap();
+        builder.set_serial_number(&BigNum::from_u32(1).unwrap().to_asn1_integer().unwrap()).unwrap();
+        builder.set_subject_name(&name).unwrap();
+        builder.set_issuer_name(&name).unwrap();
+        builder.set_pubkey(&key).unwrap();
+        builder.set_not_before(&Asn1Time::days_from_now(0).unwrap()).unwrap();
+        builder.set_not_after(&Asn1Time::days_from_now(1).unwrap()).unwrap();
+        builder.append_extension(BasicConstraints::new().critical().ca().build().unwrap()).unwrap();
+        builder.sign(&key, MessageDigest::sha256()).unwrap();
+        let certificate = builder.build();
+
+        let dir = std::env::temp_dir().join(format!("reqly-dtls-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let cert_path = dir.join("cert.pem");
+        let key_path = dir.join("key.pem");
+        std::fs::write(&cert_path, certificate.to_pem().unwrap()).unwrap();
+        std::fs::write(&key_path, key.private_key_to_pem_pkcs8().unwrap()).unwrap();
+        (cert_path, key_path)
+    }
+
+    /// A blocking UDP channel for the test DTLS server that records every datagram it reads.
+    #[derive(Debug)]
+    struct RecordingChannel {
+        socket: std::net::UdpSocket,
+        datagrams: Arc<StdMutex<Vec<Vec<u8>>>>,
+    }
+
+    impl Read for RecordingChannel {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let len = self.socket.recv(buf)?;
+            self.datagrams.lock().unwrap().push(buf[..len].to_vec());
+            Ok(len)
+        }
+    }
+
+    impl Write for RecordingChannel {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.socket.send(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_udp_dtls() {
+        let (cert_path, key_path) = self_signed_certificate();
+        let server = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let server_addr = server.local_addr().unwrap().to_string();
+        let datagrams = Arc::new(StdMutex::new(Vec::new()));
+
+        let server_cert = cert_path.clone();
+        let recorded = datagrams.clone();
+        let server = std::thread::spawn(move || {
+            let (_, client) = server.peek_from(&mut [0; 1500]).unwrap();
+            server.connect(client).unwrap();
+            let mut context = SslContext::builder(SslMethod::dtls()).unwrap();
+            context.set_certificate_file(&server_cert, SslFiletype::PEM).unwrap();
+            context.set_private_key_file(&key_path, SslFiletype::PEM).unwrap();
+            let mut ssl = Ssl::new(&context.build()).unwrap();
+            ssl.set_mtu(DTLS_MTU).unwrap();
+
+            let mut stream = ssl.accept(RecordingChannel { socket: server, datagrams: recorded }).unwrap();
+            let mut buffer = [0; 1500];
+            let len = stream.ssl_read(&mut buffer).unwrap();
+            stream.ssl_write(b"pong").unwrap();
+            buffer[..len].to_vec()
+        });
+
+        let manager = UdpManager::new("127.0.0.1:0").await.unwrap();
+        let config = DtlsConfig { ca_cert: Some(cert_path), ..DtlsConfig::default() };
+        manager.connect_dtls(&server_addr, config).await.unwrap();
+        manager.send_message(b"secret message".to_vec(), &server_addr).await.unwrap();
+        let (reply, from) = manager.receive_message().await.unwrap();
+
+        assert_eq!(server.join().unwrap(), b"secret message");
+        assert_eq!(reply, b"pong");
+        assert_eq!(from, server_addr);
+        let datagrams = datagrams.lock().unwrap();
+        assert!(!datagrams.is_empty());
+        assert!(datagrams.iter().all(|datagram| !datagram.windows(6).any(|window| window == b"secret")));
+    }
+
+    #[tokio::test]
+    async fn test_udp_dtls_untrusted_certificate() {
+        let (cert_path, key_path) = self_signed_certificate();
+        let server = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let server_addr = server.local_addr().unwrap().to_string();
+        std::thread::spawn(move || {
+            let (_, client) = server.peek_from(&mut [0; 1500]).unwrap();
+            server.connect(client).unwrap();
+            let mut context = SslContext::builder(SslMethod::dtls()).unwrap();
+            context.set_certificate_file(&cert_path, SslFiletype::PEM).unwrap();
+            context.set_private_key_file(&key_path, SslFiletype::PEM).unwrap();
+            let ssl = Ssl::new(&context.build()).unwrap();
+            let _ = ssl.accept(RecordingChannel { socket: server, datagrams: Default::default() });
+        });
+
+        // The client only trusts an unrelated self-signed certificate.
+        let (other_ca, _) = self_signed_certificate();
+        let manager = UdpManager::new("127.0.0.1:0").await.unwrap();
+        let config = DtlsConfig { ca_cert: Some(other_ca), ..DtlsConfig::default() };
+        let result = manager.connect_dtls(&server_addr, config).await;
+        assert!(matches!(result, Err(ReqlyError::Dtls(_))));
+    }
+
+    #[tokio::test]
+    async fn test_udp_ttl() {
+        let manager = UdpManager::new("127.0.0.1:0").await.unwrap();
+        manager.set_ttl(5).unwrap();
+        assert_eq!(manager.ttl().unwrap(), 5);
+    }
+
+    #[tokio::test]
+    async fn test_tcp_connection() {
+        let _manager = TcpManager::new();
+        let mut stream = TcpStream::connect("127.0.0.1:5007").await.unwrap();
+
+        let message = "hello!".to_string();
+        stream.write_all(message.as_bytes()).await.unwrap();
+
+        let mut buffer = vec![0; 1024];
+        let len = stream.read(&mut buffer).await.unwrap();
+        assert_eq!(&buffer[..len], format!("Server received: {}", message).as_bytes());
+
+        stream.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_tcp_connect_with_timeout() {
+        // A closed local port refuses at once, so the timeout needs a listener that never answers:
+        // once its backlog is full, the kernel drops further connection attempts.
+        let socket = tokio::net::TcpSocket::new_v4().unwrap();
+        socket.bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let listener = socket.listen(1).unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let mut backlog = Vec::new();
+        for _ in 0..4 {
+            if let Ok(Ok(stream)) = tokio::time::timeout(Duration::from_millis(50), TcpStream::connect(&addr)).await {
+                backlog.push(stream);
+            }
+        }
+
+        let manager = TcpManager::new();
+        let started = Instant::now();
+        let result = manager.connect_with_timeout(&addr, 50).await;
+        assert!(matches!(result, Err(ReqlyError::Timeout)));
+        assert!(started.elapsed() < Duration::from_millis(500));
+
+        drop(listener);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        assert_eq!(manager.connect_with_timeout(&addr, 1000).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_tcp_peek() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            stream.write_all(b"\x01\x02payload").await.unwrap();
+            sleep(Duration::from_secs(1)).await;
+        });
+
+        let manager = TcpManager::new();
+        let connection_id = manager.connect(&addr.to_string()).await.unwrap();
+        sleep(Duration::from_millis(50)).await;
+
+        assert_eq!(manager.peek(connection_id, 2).await.unwrap(), vec![1, 2]);
+        assert_eq!(manager.peek(connection_id, 2).await.unwrap(), vec![1, 2]);
+        assert_eq!(manager.receive_message(connection_id).await.unwrap(), b"\x01\x02payload");
+    }
+
+    #[tokio::test]
+    async fn test_tcp_read_line() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let (read, mut write) = stream.into_split();
+            let mut lines = BufReader::new(read).lines();
+            while let Ok(Some(line)) = lines.next_line().await {
+                if line == "slow" {
+                    write.write_all(b"sl").await.unwrap();
+                    sleep(Duration::from_millis(200)).await;
+                    write.write_all(b"ow\n").await.unwrap();
+                } else {
+                    write.write_all(format!("{}\r\n", line).as_bytes()).await.unwrap();
+                }
+            }
+        });
+
+        let manager = TcpManager::new();
+        let connection_id = manager.connect(&addr.to_string()).await.unwrap();
+        manager.send_message(connection_id, b"+PING\nECHO hello\n".to_vec()).await.unwrap();
+        assert_eq!(manager.read_line(connection_id, Some(1000)).await.unwrap(), "+PING");
+        assert_eq!(manager.read_line(connection_id, Some(1000)).await.unwrap(), "ECHO hello");
+
+        manager.send_message(connection_id, b"slow\n".to_vec()).await.unwrap();
+        let result = manager.read_line(connection_id, Some(100)).await;
+        assert!(matches!(result, Err(ReqlyError::Timeout)));
+        assert_eq!(manager.read_line(connection_id, None).await.unwrap(), "slow");
+
+        manager.shutdown_write(connection_id).await.unwrap();
+        let result = manager.read_line(connection_id, Some(1000)).await;
+        assert!(matches!(result, Err(ReqlyError::Io(e)) if e.kind() == io::ErrorKind::UnexpectedEof));
+    }
+
+    #[tokio::test]
+    async fn test_tcp_read_line_blocks_only_its_connection() {
+        let addr = spawn_tcp_echo_server().await;
+        let manager = Arc::new(TcpManager::new());
+        let quiet = manager.connect(&addr).await.unwrap();
+        let busy = manager.connect(&addr).await.unwrap();
+
+        let reading = {
+            let manager = manager.clone();
+            tokio::spawn(async move { manager.read_line(quiet, None).await })
+        };
+        sleep(Duration::from_millis(50)).await;
+        manager.send_message(busy, b"ping\n".to_vec()).await.unwrap();
+        assert_eq!(manager.read_line(busy, Some(1000)).await.unwrap(), "ping");
+
+        // Shutting down makes the server close the quiet connection, which ends the read.
+        tokio::time::timeout(Duration::from_secs(1), manager.shutdown()).await.unwrap().unwrap();
+        let result = reading.await.unwrap();
+        assert!(matches!(result, Err(ReqlyError::Io(e)) if e.kind() == io::ErrorKind::UnexpectedEof));
+    }
+
+    #[tokio::test]
+    async fn test_tcp_framed() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (received, mut raw) = mpsc::channel(1);
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut frame = vec![0; 4 + 300];
+            stream.read_exact(&mut frame).await.unwrap();
+            received.send(frame.clone()).await.unwrap();
+            // Echoes the frame back in two writes to exercise reassembly.
+            stream.write_all(&frame[..2]).await.unwrap();
+            sleep(Duration::from_millis(50)).await;
+            stream.write_all(&frame[2..]).await.unwrap();
+            stream.write_all(&u32::MAX.to_be_bytes()).await.unwrap();
+        });
+
+        let payload: Vec<u8> = (0..300).map(|i| (i % 256) as u8).collect();
+        let manager = TcpManager::new();
+        let connection_id = manager.connect(&addr.to_string()).await.unwrap();
+        manager.write_framed(connection_id, payload.clone()).await.unwrap();
+
+        let frame = raw.recv().await.unwrap();
+        assert_eq!(frame[..4], [0, 0, 1, 44]);
+        assert_eq!(frame[4..], payload[..]);
+        assert_eq!(manager.read_framed(connection_id).await.unwrap(), payload);
+
+        let result = manager.read_framed(connection_id).await;
+        assert!(matches!(result, Err(ReqlyError::Io(e)) if e.kind() == io::ErrorKind::InvalidData));
+    }
+
+    #[tokio::test]
+    async fn test_tcp_read_framed_blocks_only_its_connection() {
+        let addr = spawn_tcp_echo_server().await;
+        let manager = Arc::new(TcpManager::new());
+        let quiet = manager.connect(&addr).await.unwrap();
+        let busy = manager.connect(&addr).await.unwrap();
+
+        // The quiet connection gets only part of a frame, so its read stays pending.
+        manager.send_message(quiet, vec![0, 0, 0, 10, 1]).await.unwrap();
+        let reading = {
+            let manager = manager.clone();
+            tokio::spawn(async move { manager.read_framed(quiet).await })
+        };
+        sleep(Duration::from_millis(50)).await;
+        manager.write_framed(busy, b"frame".to_vec()).await.unwrap();
+        assert_eq!(manager.read_framed(busy).await.unwrap(), b"frame");
+
+        tokio::time::timeout(Duration::from_secs(1), manager.shutdown()).await.unwrap().unwrap();
+        let result = reading.await.unwrap();
+        assert!(matches!(result, Err(ReqlyError::Io(e)) if e.kind() == io::ErrorKind::UnexpectedEof));
+    }
+
+    #[tokio::test]
+    async fn test_tcp_stats() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = vec![0; 8];
+            stream.read_exact(&mut request).await.unwrap();
+            stream.write_all(b"answer").await.unwrap();
+        });
+
+        let manager = TcpManager::new();
+        let connection_id = manager.connect(&addr.to_string()).await.unwrap();
+        assert_eq!(manager.stats(connection_id).await, Some(TcpStats::default()));
+
+        manager.send_message(connection_id, b"hello".to_vec()).await.unwrap();
+        manager.send_message(connection_id, b"!!!".to_vec()).await.unwrap();
+        assert_eq!(manager.receive_message(connection_id).await.unwrap(), b"answer");
+        assert!(manager.receive_message(connection_id).await.unwrap().is_empty());
+
+        let stats = TcpStats { bytes_sent: 8, bytes_received: 6, messages_sent: 2, messages_received: 1 };
+        assert_eq!(manager.stats(connection_id).await, Some(stats));
+        assert_eq!(manager.stats(connection_id + 1).await, None);
+    }
+
+    #[tokio::test]
+    async fn test_tcp_shutdown() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let peer = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            // Reading late keeps the send below in progress when `shutdown` is called.
+            sleep(Duration::from_millis(200)).await;
+            let mut received = Vec::new();
+            stream.read_to_end(&mut received).await.unwrap();
+            received
+        });
+
+        let manager = Arc::new(TcpManager::new());
+        let listen_addr = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let listening = {
+            let manager = manager.clone();
+            tokio::spawn(async move { manager.listen(&listen_addr.to_string()).await.map_err(|e| e.to_string()) })
+        };
+        let connection_id = manager.connect(&addr.to_string()).await.unwrap();
+        let message = vec![7; 8 * 1024 * 1024];
+        let sending = {
+            let (manager, message) = (manager.clone(), message.clone());
+            tokio::spawn(async move { manager.send_message(connection_id, message).await.map_err(|e| e.to_string()) })
+        };
+        sleep(Duration::from_millis(50)).await;
+        assert!(!sending.is_finished());
+        manager.close_connection(connection_id + 1).await;
+
+        manager.shutdown().await.unwrap();
+        assert_eq!(sending.await.unwrap(), Ok(()));
+        // The peer sees EOF only after the whole message.
+        assert_eq!(peer.await.unwrap(), message);
+        assert_eq!(listening.await.unwrap(), Ok(()));
+        assert!(manager.listen(&listen_addr.to_string()).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_tcp_shutdown_write() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            stream.read_to_end(&mut request).await.unwrap();
+            let response = format!("received {} bytes", request.len());
+            stream.write_all(response.as_bytes()).await.unwrap();
+        });
+
+        let manager = TcpManager::new();
+        let connection_id = manager.connect(&addr.to_string()).await.unwrap();
+        manager.send_message(connection_id, vec![b'x'; 3000]).await.unwrap();
+        manager.shutdown_write(connection_id).await.unwrap();
+
+        let mut response = Vec::new();
+        loop {
+            let chunk = manager.receive_message(connection_id).await.unwrap();
+            if chunk.is_empty() {
+                break;
+            }
+            response.extend_from_slice(&chunk);
+        }
+        assert_eq!(response, b"received 3000 bytes");
+
+        assert!(matches!(
+            manager.shutdown_write(connection_id + 1).await,
+            Err(ReqlyError::ConnectionNotFound(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_tcp_close_keeps_other_ids() {
+        let addr = spawn_tcp_echo_server().await;
+
+        let manager = TcpManager::new();
+        let first = manager.connect(&addr).await.unwrap();
+        let second = manager.connect(&addr).await.unwrap();
+        assert_eq!((first, second), (0, 1));
+
+        // An idle peek on the second connection must not hold up the others.
+        let manager = Arc::new(manager);
+        let peeking = {
+            let manager = manager.clone();
+            tokio::spawn(async move { manager.peek(second, 1).await.unwrap() })
+        };
+        sleep(Duration::from_millis(50)).await;
+        manager.close_connection(first).await;
+        while manager.stats(first).await.is_some() {
+            sleep(Duration::from_millis(10)).await;
+        }
+        assert!(matches!(manager.receive_message(first).await, Err(ReqlyError::ConnectionNotFound(0))));
+
+        manager.send_message(second, b"still here".to_vec()).await.unwrap();
+        assert_eq!(manager.receive_message(second).await.unwrap(), b"still here");
+        assert_eq!(peeking.await.unwrap(), b"s");
+
+        let third = manager.connect(&addr).await.unwrap();
+        assert_eq!(third, 2);
+    }
+}